@@ -0,0 +1,377 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use ed25519_dalek::{Signer, SigningKey};
+use hmac::{Hmac, Mac};
+use json::JsonValue;
+use sha2::{Digest, Sha256};
+use wamp_helpers::messages::Authenticate;
+
+use crate::error::Error;
+
+/// Answers the router's CHALLENGE while joining a realm. `WampClient::join_with_methods`
+/// announces several authenticators and picks the one whose `authmethod` the router
+/// challenges with.
+pub trait Authenticator: Send + Sync {
+    /// Method announced in the HELLO `authmethods` list.
+    fn authmethod(&self) -> &str;
+
+    /// Identity announced as `authid` in HELLO.
+    fn authid(&self) -> Option<&str> {
+        None
+    }
+
+    /// Extra data announced as `authextra` in HELLO.
+    fn authextra(&self) -> Option<JsonValue> {
+        None
+    }
+
+    /// Builds the AUTHENTICATE reply from the `extra` dictionary of the CHALLENGE.
+    fn authenticate(&self, extra: &JsonValue) -> Result<Authenticate, Error>;
+}
+
+fn cannot_authenticate(message: &str) -> Error {
+    Error::Authentication(message.to_string())
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Joins without credentials. Routers never challenge anonymous sessions.
+pub struct Anonymous;
+
+impl Authenticator for Anonymous {
+    fn authmethod(&self) -> &str {
+        "anonymous"
+    }
+
+    fn authenticate(&self, _extra: &JsonValue) -> Result<Authenticate, Error> {
+        Err(cannot_authenticate("anonymous sessions cannot answer a challenge"))
+    }
+}
+
+/// Ticket authentication: the ticket is sent as the signature as-is.
+pub struct Ticket {
+    authid: Option<String>,
+    ticket: String
+}
+
+impl Ticket {
+    pub fn new(ticket: &str) -> Ticket {
+        Ticket { authid: None, ticket: ticket.to_string() }
+    }
+
+    pub fn with_authid(authid: &str, ticket: &str) -> Ticket {
+        Ticket { authid: Some(authid.to_string()), ticket: ticket.to_string() }
+    }
+}
+
+impl Authenticator for Ticket {
+    fn authmethod(&self) -> &str {
+        "ticket"
+    }
+
+    fn authid(&self) -> Option<&str> {
+        self.authid.as_deref()
+    }
+
+    fn authenticate(&self, _extra: &JsonValue) -> Result<Authenticate, Error> {
+        Ok(Authenticate { signature: self.ticket.clone(), details: json::object! {} })
+    }
+}
+
+/// WAMP Challenge-Response Authentication. When the challenge carries a `salt`, the
+/// secret is first derived with PBKDF2-HMAC-SHA256.
+pub struct WampCra {
+    authid: String,
+    secret: String
+}
+
+impl WampCra {
+    pub fn new(authid: &str, secret: &str) -> WampCra {
+        WampCra { authid: authid.to_string(), secret: secret.to_string() }
+    }
+
+    /// Derives the salted key the router stores for `secret`, base64 encoded.
+    pub fn derive_key(secret: &str, salt: &str, iterations: u32, keylen: usize) -> String {
+        let mut key = vec![0u8; keylen];
+        pbkdf2::pbkdf2_hmac::<Sha256>(secret.as_bytes(), salt.as_bytes(), iterations, &mut key);
+        BASE64.encode(key)
+    }
+
+    /// Signs `challenge` with `key`, base64 encoded.
+    pub fn sign(key: &str, challenge: &str) -> String {
+        BASE64.encode(hmac_sha256(key.as_bytes(), challenge.as_bytes()))
+    }
+}
+
+impl Authenticator for WampCra {
+    fn authmethod(&self) -> &str {
+        "wampcra"
+    }
+
+    fn authid(&self) -> Option<&str> {
+        Some(&self.authid)
+    }
+
+    fn authenticate(&self, extra: &JsonValue) -> Result<Authenticate, Error> {
+        let challenge = extra["challenge"].as_str()
+            .ok_or_else(|| cannot_authenticate("wampcra challenge without a challenge string"))?;
+
+        let key = match extra["salt"].as_str() {
+            Some(salt) => WampCra::derive_key(
+                &self.secret,
+                salt,
+                extra["iterations"].as_u32().unwrap_or(1000),
+                extra["keylen"].as_usize().unwrap_or(32)
+            ),
+            None => self.secret.clone()
+        };
+
+        Ok(Authenticate { signature: WampCra::sign(&key, challenge), details: json::object! {} })
+    }
+}
+
+/// WAMP-Cryptosign: signs the challenge with an Ed25519 private key. The public key is
+/// announced in `authextra` so the router can pick the matching principal.
+pub struct CryptoSign {
+    authid: Option<String>,
+    key: SigningKey
+}
+
+impl CryptoSign {
+    pub fn new(authid: Option<&str>, private_key: &[u8; 32]) -> CryptoSign {
+        CryptoSign { authid: authid.map(|a| a.to_string()), key: SigningKey::from_bytes(private_key) }
+    }
+
+    /// Creates the authenticator from a hex encoded 32 byte private key seed.
+    pub fn from_hex(authid: Option<&str>, private_key: &str) -> Result<CryptoSign, Error> {
+        let bytes: [u8; 32] = hex::decode(private_key).ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| cannot_authenticate("cryptosign private key must be 32 hex encoded bytes"))?;
+        Ok(CryptoSign::new(authid, &bytes))
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().to_bytes())
+    }
+
+    /// Signs a hex encoded challenge. The result is the hex encoded signature followed by
+    /// the challenge itself.
+    pub fn sign(&self, challenge: &str) -> Result<String, Error> {
+        let challenge = hex::decode(challenge)
+            .map_err(|_| cannot_authenticate("cryptosign challenge is not hex encoded"))?;
+        let signature = self.key.sign(&challenge);
+        Ok(hex::encode(signature.to_bytes()) + &hex::encode(challenge))
+    }
+}
+
+impl Authenticator for CryptoSign {
+    fn authmethod(&self) -> &str {
+        "cryptosign"
+    }
+
+    fn authid(&self) -> Option<&str> {
+        self.authid.as_deref()
+    }
+
+    fn authextra(&self) -> Option<JsonValue> {
+        Some(json::object! { pubkey: self.public_key() })
+    }
+
+    fn authenticate(&self, extra: &JsonValue) -> Result<Authenticate, Error> {
+        let challenge = extra["challenge"].as_str()
+            .ok_or_else(|| cannot_authenticate("cryptosign challenge without a challenge string"))?;
+        Ok(Authenticate { signature: self.sign(challenge)?, details: json::object! {} })
+    }
+}
+
+/// WAMP-SCRAM with the `argon2id13` and `pbkdf2` key derivation functions. Channel
+/// binding is not supported.
+pub struct WampScram {
+    authid: String,
+    password: String,
+    /// Client nonce of the last HELLO, generated anew for every join.
+    nonce: Mutex<String>
+}
+
+impl WampScram {
+    pub fn new(authid: &str, password: &str) -> WampScram {
+        WampScram { authid: authid.to_string(), password: password.to_string(), nonce: Mutex::default() }
+    }
+
+    fn nonce(&self) -> MutexGuard<'_, String> {
+        self.nonce.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn salted_password(&self, extra: &JsonValue) -> Result<[u8; 32], Error> {
+        let salt = extra["salt"].as_str()
+            .and_then(|salt| BASE64.decode(salt).ok())
+            .ok_or_else(|| cannot_authenticate("wamp-scram challenge without a base64 salt"))?;
+        let iterations = extra["iterations"].as_u32()
+            .ok_or_else(|| cannot_authenticate("wamp-scram challenge without iterations"))?;
+
+        let mut salted = [0u8; 32];
+        match extra["kdf"].as_str() {
+            Some("argon2id13") | Some("argon2id-13") => {
+                let memory = extra["memory"].as_u32()
+                    .ok_or_else(|| cannot_authenticate("argon2id13 challenge without memory"))?;
+                let params = Params::new(memory, iterations, 1, Some(32))
+                    .map_err(|e| cannot_authenticate(&e.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(self.password.as_bytes(), &salt, &mut salted)
+                    .map_err(|e| cannot_authenticate(&e.to_string()))?;
+            },
+            Some("pbkdf2") => {
+                pbkdf2::pbkdf2_hmac::<Sha256>(self.password.as_bytes(), &salt, iterations, &mut salted);
+            },
+            _ => return Err(cannot_authenticate("unsupported wamp-scram kdf"))
+        }
+        Ok(salted)
+    }
+}
+
+impl Authenticator for WampScram {
+    fn authmethod(&self) -> &str {
+        "wamp-scram"
+    }
+
+    fn authid(&self) -> Option<&str> {
+        Some(&self.authid)
+    }
+
+    fn authextra(&self) -> Option<JsonValue> {
+        let nonce = BASE64.encode(rand::random::<[u8; 16]>());
+        *self.nonce() = nonce.clone();
+        Some(json::object! { nonce: nonce, channel_binding: null })
+    }
+
+    fn authenticate(&self, extra: &JsonValue) -> Result<Authenticate, Error> {
+        let client_nonce = self.nonce().clone();
+        let server_nonce = extra["nonce"].as_str()
+            .filter(|nonce| !client_nonce.is_empty() && nonce.starts_with(&client_nonce))
+            .ok_or_else(|| cannot_authenticate("wamp-scram server nonce does not extend the client nonce"))?;
+
+        let auth_message = format!(
+            "n={},r={},r={},s={},i={},c=,r={}",
+            self.authid,
+            client_nonce,
+            server_nonce,
+            extra["salt"].as_str().unwrap_or_default(),
+            extra["iterations"].as_u32().unwrap_or_default(),
+            server_nonce
+        );
+
+        let client_key = hmac_sha256(&self.salted_password(extra)?, b"Client Key");
+        let stored_key = Sha256::digest(client_key);
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let client_proof: Vec<u8> = client_key.iter().zip(client_signature.iter()).map(|(k, s)| k ^ s).collect();
+
+        Ok(Authenticate {
+            signature: BASE64.encode(client_proof),
+            details: json::object! { nonce: server_nonce, channel_binding: null, cbind_data: null }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wampcra_derives_salted_key() {
+        assert_eq!(WampCra::derive_key("L3L1YUE8Txlw", "salt123", 1000, 32), "qzcdsr9uu/L5hnss3kjNTRe490ETgA70ZBaB5rvnJ5Y=");
+    }
+
+    #[test]
+    fn wampcra_signs_challenge() {
+        let cra = WampCra::new("peter", "L3L1YUE8Txlw");
+        let authenticate = cra.authenticate(&json::object! { challenge: "[1, 2, 3]" }).unwrap();
+        assert_eq!(authenticate.signature, "1njQtmmeYO41N5EWEzD2kAjjEKRZ5kPZt/TzpYXOzR0=");
+    }
+
+    #[test]
+    fn wampcra_signs_with_salted_key() {
+        let cra = WampCra::new("peter", "L3L1YUE8Txlw");
+        let authenticate = cra.authenticate(&json::object! {
+            challenge: "[1, 2, 3]",
+            salt: "salt123",
+            iterations: 1000,
+            keylen: 32
+        }).unwrap();
+        assert_eq!(authenticate.signature, WampCra::sign("qzcdsr9uu/L5hnss3kjNTRe490ETgA70ZBaB5rvnJ5Y=", "[1, 2, 3]"));
+    }
+
+    #[test]
+    fn cryptosign_signs_challenge() {
+        let cryptosign = CryptoSign::from_hex(None, "4d57d97a68f555696620a6d849c0ce582568518d729eb753dc7c732de2804510").unwrap();
+        assert_eq!(cryptosign.public_key(), "1adfc8bfe1d35616e64dffbd900096f23b066f914c8c2ffbb66f6075b96e116d");
+
+        let authenticate = cryptosign.authenticate(&json::object! { challenge: "ff".repeat(32) }).unwrap();
+        assert_eq!(
+            authenticate.signature,
+            "b32675b221f08593213737bef8240e7c15228b07028e19595294678c90d11c0cae80a357331bfc5cc9fb71081464e6e75013517c2cf067ad566a6b7b728e5d03".to_string() + &"ff".repeat(32)
+        );
+
+        let authenticate = cryptosign.authenticate(&json::object! { challenge: "00".repeat(32) }).unwrap();
+        assert_eq!(
+            authenticate.signature,
+            "19f750b7b1702fd2af78662852646287cff056b5c58ec576f014fe4b1d4591bd74d2841b0fb8fcc9cfb6f15a8c78b520bc095e51be77b9bd55a7d18ce1bde501".to_string() + &"00".repeat(32)
+        );
+    }
+
+    /// Client proof for the nonces and salt of the WAMP-SCRAM example, password "pencil".
+    /// Expected proofs were cross-checked against OpenSSL's Argon2id and PBKDF2.
+    fn scram_proof(kdf: &str, memory: JsonValue) -> Authenticate {
+        let scram = WampScram { authid: "user".to_string(), password: "pencil".to_string(), nonce: Mutex::new("egVDf3DMJh0=".to_string()) };
+        scram.authenticate(&json::object! {
+            nonce: "egVDf3DMJh0=SBmkFIh7sSo=",
+            salt: "aBc+fx0NAVA=",
+            kdf: kdf,
+            iterations: 4096,
+            memory: memory
+        }).unwrap()
+    }
+
+    #[test]
+    fn scram_computes_client_proof() {
+        let authenticate = scram_proof("pbkdf2", JsonValue::Null);
+        assert_eq!(authenticate.signature, "gxhBmdiKPWN4bQdkBh54u8lYPxCa8kVSpamruiv9sEo=");
+        assert_eq!(authenticate.details["nonce"], "egVDf3DMJh0=SBmkFIh7sSo=");
+    }
+
+    #[test]
+    fn scram_computes_argon2id13_client_proof() {
+        let authenticate = scram_proof("argon2id13", 512.into());
+        assert_eq!(authenticate.signature, "K3Riqycd9I0vu0AedTMlPTC4Y3biCn7v7FJU2TAdF+I=");
+        assert_eq!(authenticate.details["nonce"], "egVDf3DMJh0=SBmkFIh7sSo=");
+    }
+
+    #[test]
+    fn scram_announces_a_fresh_nonce_for_every_join() {
+        let scram = WampScram::new("user", "pencil");
+        let first = scram.authextra().unwrap()["nonce"].to_string();
+        let second = scram.authextra().unwrap()["nonce"].to_string();
+        assert_ne!(first, second);
+
+        let challenge = |nonce: &str| json::object! { nonce: nonce.to_string() + "SBmkFIh7sSo=", salt: "aBc+fx0NAVA=", kdf: "pbkdf2", iterations: 16 };
+        assert!(scram.authenticate(&challenge(&first)).is_err());
+        assert!(scram.authenticate(&challenge(&second)).is_ok());
+    }
+
+    #[test]
+    fn scram_rejects_foreign_server_nonce() {
+        let scram = WampScram::new("user", "pencil");
+        scram.authextra();
+        assert!(scram.authenticate(&json::object! {
+            nonce: "SBmkFIh7sSo=",
+            salt: "aBc+fx0NAVA=",
+            kdf: "pbkdf2",
+            iterations: 4096
+        }).is_err());
+    }
+}
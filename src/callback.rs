@@ -1,6 +1,13 @@
-use wamp_helpers::messages::{Welcome, Challenge, Abort, Goodbye, ErrorMessage, Interrupt, Yield, Event, Subscribed, MessageResult, Published, Unsubscribed, Registered, Unregistered, Subscribe, WampMessageTrait, Call, Publish, Unsubscribe, Register, Unregister};
+use std::{collections::{hash_map::Entry, HashMap}, future::Future, pin::Pin, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, MutexGuard, PoisonError}, task::{Context as TaskContext, Poll}};
 
-use crate::{client::Context, error::Error};
+use futures::{stream::BoxStream, Stream};
+use json::JsonValue;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use wamp_helpers::messages::{Welcome, Challenge, Abort, Goodbye, ErrorMessage, Interrupt, Yield, Event, Subscribed, MessageResult, Published, Unsubscribed, Registered, Unregistered, Subscribe, WampMessageTrait, Call, Cancel, Publish, Unsubscribe, Register, Unregister, Invocation, Hello, Events as WampEvents};
+
+use crate::{auth::Authenticator, client::Context, error::Error, options::{CallOptions, CancelMode, Match, PublishOptions, RegisterOptions, SubscribeOptions}, ppt::{self, KeyRing, PayloadKey}, reconnect::Lifecycle, session::{hello_details, RequestIds, Roles}, transport::BoxFuture, typed::{from_payload, to_payload}};
 
 
 
@@ -11,101 +18,1341 @@ pub enum Events {
     Goodbye(Callback<Goodbye>),
     Error(Callback<ErrorMessage>),
     Interrupt(Callback<Interrupt>),
-    Yield(Callback<Yield>)
+    Yield(Callback<Yield>),
+    Lifecycle(Callback<Lifecycle>),
+    /// Errors raised while receiving, such as unparsable frames or protocol violations.
+    Failure(Callback<Error>)
+}
+
+pub(crate) type Callback<T> = Box<dyn Fn(Context, T) -> Context + Send + Sync>;
+
+/// Handler for a registered procedure. The returned result is sent back to the caller
+/// as a YIELD or an ERROR for the INVOCATION.
+pub(crate) type Procedure = Box<dyn Fn(Context, Invocation) -> (Context, InvocationResult) + Send + Sync>;
+
+/// Handler for a registered procedure that completes asynchronously. The session driver
+/// runs the returned future on its own task and sends the YIELD or ERROR once it resolves.
+pub(crate) type AsyncProcedure = Box<dyn Fn(Invocation) -> BoxFuture<'static, InvocationResult> + Send + Sync>;
+
+/// Handler for a registered procedure that produces a stream of results. When the caller
+/// asked for progress, every result but the last is sent as a progressive YIELD.
+pub(crate) type StreamingProcedure = Box<dyn Fn(Invocation) -> BoxStream<'static, InvocationResult> + Send + Sync>;
+
+pub(crate) enum Callee {
+    Sync(Procedure),
+    Async(AsyncProcedure),
+    Streaming(StreamingProcedure)
+}
+
+/// An invocation of an async or streaming procedure, waiting to be run by the driver.
+pub(crate) enum Running {
+    Future(BoxFuture<'static, InvocationResult>),
+    Stream { progress: bool, results: BoxStream<'static, InvocationResult> }
+}
+
+/// Sending half of a request that is waiting for the router's reply.
+pub(crate) type Responder<T> = oneshot::Sender<Result<T, ErrorMessage>>;
+
+/// Future resolving to the router's reply for a request.
+///
+/// Resolves with `Error::Wamp` when the router answers with ERROR, and with
+/// `Error::SessionClosed` when the session goes away before a reply arrives.
+pub struct PendingResponse<T> {
+    request: u64,
+    receiver: oneshot::Receiver<Result<T, ErrorMessage>>
+}
+
+impl<T> PendingResponse<T> {
+    pub(crate) fn new(request: u64) -> (Responder<T>, PendingResponse<T>) {
+        let (sender, receiver) = oneshot::channel();
+        (sender, PendingResponse { request, receiver })
+    }
+
+    /// Id the session assigned to the request.
+    pub fn request(&self) -> u64 {
+        self.request
+    }
+}
+
+impl<T> Future for PendingResponse<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().receiver).poll(cx).map(|reply| match reply {
+            Ok(Ok(message)) => Ok(message),
+            Ok(Err(error)) => Err(error.into()),
+            Err(_) => Err(Error::SessionClosed)
+        })
+    }
+}
+
+/// Stream of the results of a call made with `receive_progress`. Yields every progressive
+/// RESULT and ends after the final RESULT or an ERROR.
+pub struct ProgressiveResults {
+    request: u64,
+    receiver: mpsc::UnboundedReceiver<Result<MessageResult, Error>>
+}
+
+impl ProgressiveResults {
+    /// Id the session assigned to the call.
+    pub fn request(&self) -> u64 {
+        self.request
+    }
+}
+
+impl Stream for ProgressiveResults {
+    type Item = Result<MessageResult, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+/// Invocation of an async or streaming procedure, for the driver to run.
+pub(crate) struct StartedInvocation {
+    pub(crate) request: u64,
+    /// Cancelled when the router sends INTERRUPT.
+    pub(crate) interrupted: CancellationToken,
+    pub(crate) running: Running,
+    /// Key encrypting the results, when the procedure's URI has one.
+    pub(crate) key: Option<PayloadKey>
+}
+
+/// Locks the shared handler. A handler that panicked while holding the lock has already
+/// been reported, so the poisoned state is recovered instead of propagated.
+pub(crate) fn lock(handler: &Mutex<CallbackHandler>) -> MutexGuard<'_, CallbackHandler> {
+    handler.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// HELLO waiting for WELCOME, with the authenticators announced for answering a CHALLENGE.
+pub(crate) type JoinRequest = (oneshot::Sender<Result<Welcome, Error>>, Vec<Arc<dyn Authenticator>>);
+
+/// Realm, roles and authenticators of a join, to join again after a reconnect.
+pub(crate) type Rejoin = (String, Roles, Vec<Arc<dyn Authenticator>>);
+
+/// Removes the responder waiting on `request` and hands it the reply.
+fn resolve<T>(pending: &mut Vec<(u64, Responder<T>)>, request: u64, reply: Result<T, ErrorMessage>) {
+    if let Some(index) = pending.iter().position(|i| i.0 == request) {
+        let (_, responder) = pending.remove(index);
+        let _ = responder.send(reply);
+    }
+}
+
+/// YIELD or ERROR answering the INVOCATION `request` with a procedure's result. With
+/// `progress`, the YIELD is a progressive result and more are to follow. With `key`, the
+/// payload of the YIELD or ERROR is encrypted end to end.
+pub(crate) fn invocation_reply(request: u64, result: InvocationResult, progress: bool, key: Option<&PayloadKey>) -> Result<JsonValue, Error> {
+    let mut options = json::object! {};
+    if progress {
+        options["progress"] = true.into();
+    }
+    let reply = match result {
+        Ok(mut payload) => {
+            if let Some(key) = key {
+                ppt::seal(key, &mut options, &mut payload.args, &mut payload.kwargs)?;
+            }
+            Yield {
+                request,
+                options,
+                args: payload.args,
+                kwargs: payload.kwargs
+            }.to_json()
+        },
+        Err(mut error) => {
+            let mut details = json::object! {};
+            if let Some(key) = key {
+                ppt::seal(key, &mut details, &mut error.args, &mut error.kwargs)?;
+            }
+            ErrorMessage {
+                request_type: Invocation::ID,
+                request,
+                details,
+                error: error.error,
+                args: error.args,
+                kwargs: error.kwargs
+            }.to_json()
+        }
+    };
+    reply.map_err(Error::JsonError)
+}
+
+/// Like `resolve`, for requests several callers can be waiting on.
+fn resolve_all<T: Clone>(pending: &mut Vec<(u64, Responder<T>)>, request: u64, reply: Result<T, ErrorMessage>) {
+    while pending.iter().any(|i| i.0 == request) {
+        resolve(pending, request, reply.clone());
+    }
+}
+
+/// SUBSCRIBE of a router subscription and the local handlers sharing it, keyed by
+/// handler id.
+pub(crate) struct TopicSubscription {
+    pub(crate) subscribe: Subscribe,
+    pub(crate) handlers: Vec<(u64, Callback<ReceivedEvent>)>
+}
+
+impl TopicSubscription {
+    fn matches(&self, topic: &str, matching: Match) -> bool {
+        self.subscribe.topic == topic && Match::from_options(&self.subscribe.options) == matching
+    }
+
+    fn has_handler(&self, handler: u64) -> bool {
+        self.handlers.iter().any(|i| i.0 == handler)
+    }
+}
+
+static NEXT_HANDLER: AtomicU64 = AtomicU64::new(1);
+
+/// Id of a local event handler, unique across every session of the process.
+fn next_handler() -> u64 {
+    NEXT_HANDLER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// EVENT delivered to a subscription handler.
+#[derive(Debug, Clone)]
+pub struct ReceivedEvent {
+    /// Topic the event was published to. For prefix and wildcard subscriptions this is
+    /// the concrete topic the router reports in `details.topic`.
+    pub topic: String,
+    /// Session of the publisher, when it published with `disclose_me`.
+    pub publisher: Option<u64>,
+    pub publisher_authid: Option<String>,
+    pub publisher_authrole: Option<String>,
+    /// Trust level the router assigned to the publication.
+    pub trustlevel: Option<u64>,
+    pub event: Event
+}
+
+impl ReceivedEvent {
+    fn new(topic: String, event: Event) -> ReceivedEvent {
+        ReceivedEvent {
+            topic,
+            publisher: event.details["publisher"].as_u64(),
+            publisher_authid: detail(&event.details, "publisher_authid"),
+            publisher_authrole: detail(&event.details, "publisher_authrole"),
+            trustlevel: event.details["trustlevel"].as_u64(),
+            event
+        }
+    }
+
+    /// Event of `subscription` stored in the router's event history, as one entry of the
+    /// list `wamp.subscription.get_events` returns.
+    pub(crate) fn from_history(subscription: &Subscription, entry: &JsonValue) -> Result<ReceivedEvent, Error> {
+        let publication = entry["publication"].as_u64()
+            .ok_or_else(|| Error::Protocol(format!("history event without publication id: {entry}")))?;
+        let mut details = json::object! {};
+        for key in ["publisher", "publisher_authid", "publisher_authrole", "trustlevel"] {
+            if !entry[key].is_null() {
+                details[key] = entry[key].clone();
+            }
+        }
+        let topic = entry["topic"].as_str().unwrap_or(&subscription.topic).to_string();
+        Ok(ReceivedEvent::new(topic, Event {
+            subscription: subscription.subscription,
+            publication,
+            details,
+            args: entry["args"].clone(),
+            kwargs: entry["kwargs"].clone()
+        }))
+    }
+
+    /// Decodes the event's args and kwargs as described in `typed::from_payload`.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        from_payload(&self.event.args, &self.event.kwargs)
+    }
+}
+
+/// Details of an INVOCATION that identify the call, for authorization in procedure
+/// handlers. The caller fields are only set when the caller called with `disclose_me`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvocationContext {
+    /// Procedure the caller called. For prefix and wildcard registrations this is the
+    /// concrete URI rather than the registered pattern.
+    pub procedure: String,
+    /// Session of the caller.
+    pub caller: Option<u64>,
+    pub caller_authid: Option<String>,
+    pub caller_authrole: Option<String>,
+    /// Trust level the router assigned to the call.
+    pub trustlevel: Option<u64>
+}
+
+impl InvocationContext {
+    pub fn new(invocation: &Invocation) -> InvocationContext {
+        let details = &invocation.details;
+        InvocationContext {
+            procedure: detail(details, "procedure").unwrap_or_default(),
+            caller: details["caller"].as_u64(),
+            caller_authid: detail(details, "caller_authid"),
+            caller_authrole: detail(details, "caller_authrole"),
+            trustlevel: details["trustlevel"].as_u64()
+        }
+    }
+}
+
+fn detail(details: &JsonValue, key: &str) -> Option<String> {
+    details[key].as_str().map(|s| s.to_string())
+}
+
+/// A local handler subscribed to a topic. Handlers subscribed to the same topic with the
+/// same match policy share one router subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub(crate) handler: u64,
+    pub subscription: u64,
+    pub topic: String,
+    pub matching: Match
+}
+
+/// Future resolving to the `Subscription` once the router has acknowledged it. Resolves
+/// right away when the topic is already subscribed.
+pub struct PendingSubscription {
+    handler: u64,
+    topic: String,
+    matching: Match,
+    subscribed: PendingResponse<Subscribed>
+}
+
+impl Future for PendingSubscription {
+    type Output = Result<Subscription, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut this.subscribed).poll(cx).map(|reply| reply.map(|subscribed| Subscription {
+            handler: this.handler,
+            subscription: subscribed.subscription,
+            topic: this.topic.clone(),
+            matching: this.matching
+        }))
+    }
 }
 
-pub(crate) type Callback<T> = Box<dyn Fn(Context, T) -> Context>;
+pub type InvocationResult = Result<Payload, ProcedureError>;
+
+/// Positional and keyword arguments yielded by a procedure.
+#[derive(Debug, Clone)]
+pub struct Payload {
+    pub args: JsonValue,
+    pub kwargs: JsonValue
+}
+
+impl Payload {
+    pub fn new(args: JsonValue, kwargs: JsonValue) -> Payload {
+        Payload { args, kwargs }
+    }
+
+    /// Encodes `value` as described in `typed::to_payload`.
+    pub fn typed<T: Serialize + ?Sized>(value: &T) -> Result<Payload, Error> {
+        let (args, kwargs) = to_payload(value)?;
+        Ok(Payload { args, kwargs })
+    }
+}
+
+/// Error URI and payload returned to the caller when a procedure fails.
+#[derive(Debug, Clone)]
+pub struct ProcedureError {
+    pub error: String,
+    pub args: JsonValue,
+    pub kwargs: JsonValue
+}
+
+impl ProcedureError {
+    pub fn new(error: &str) -> ProcedureError {
+        ProcedureError { error: error.to_string(), args: JsonValue::Null, kwargs: JsonValue::Null }
+    }
+}
+
+/// Arguments that do not decode become `wamp.error.invalid_argument`, and any other
+/// error `wamp.error.runtime_error`, with the error message as the only argument.
+impl From<Error> for ProcedureError {
+    fn from(error: Error) -> Self {
+        let uri = match error {
+            Error::Payload(_) => "wamp.error.invalid_argument",
+            _ => "wamp.error.runtime_error"
+        };
+        ProcedureError { args: json::array![error.to_string()], ..ProcedureError::new(uri) }
+    }
+}
 
 pub struct CallbackHandler {
     pub(crate) callbacks: Vec<Events>,
-    pub(crate) subscriptions: Vec<(u64, Callback<Event>, Option<u64>)>,
-    pub(crate) on_subscribed: Vec<(u64, Callback<Result<Subscribed, ErrorMessage>>)>,
-    pub(crate) call_results: Vec<(u64, Callback<Result<MessageResult, ErrorMessage>>)>,
-    pub(crate) publish_callbacks: Vec<(u64, Callback<Result<Published, ErrorMessage>>)>,
-    pub(crate) unsubscribe_callbacks: Vec<(u64, Callback<Result<Unsubscribed, ErrorMessage>>)>,
-    pub(crate) register_callbacks: Vec<(u64, Callback<Result<Registered, ErrorMessage>>)>,
-    pub(crate) unregistered_callbacks: Vec<(u64, Callback<Result<Unregistered, ErrorMessage>>)>,
-    pub(crate) message_queue: Vec<String>
+    /// Lifecycle callbacks belonging to one subscription handler, by handler id, dropped
+    /// together with the handler.
+    pub(crate) handler_lifecycles: HashMap<u64, Callback<Lifecycle>>,
+    /// Subscriptions waiting for SUBSCRIBED, by request id.
+    pub(crate) pending_subscriptions: HashMap<u64, TopicSubscription>,
+    /// Subscriptions acknowledged by the router, by subscription id.
+    pub(crate) subscriptions: HashMap<u64, TopicSubscription>,
+    pub(crate) on_subscribed: Vec<(u64, Responder<Subscribed>)>,
+    pub(crate) call_results: Vec<(u64, Responder<MessageResult>)>,
+    pub(crate) progressive_calls: Vec<(u64, mpsc::UnboundedSender<Result<MessageResult, Error>>)>,
+    /// Procedures of the calls sent encrypted, by request id, whose results have to come
+    /// back encrypted as well.
+    pub(crate) sealed_calls: HashMap<u64, String>,
+    pub(crate) publish_callbacks: Vec<(u64, Responder<Published>)>,
+    pub(crate) unsubscribe_callbacks: Vec<(u64, Responder<Unsubscribed>)>,
+    pub(crate) register_callbacks: Vec<(u64, Responder<Registered>)>,
+    pub(crate) unregistered_callbacks: Vec<(u64, Responder<Unregistered>)>,
+    pub(crate) registrations: Vec<(Register, Callee, Option<u64>)>,
+    /// Unsubscribes made through a handler's `Context`, applied by `merge` to the session,
+    /// which holds the subscriptions.
+    pub(crate) unsubscribes: Vec<(u64, Subscription, Responder<Unsubscribed>)>,
+    /// Unregisters made through a handler's `Context`, applied by `merge` like `unsubscribes`.
+    pub(crate) unregisters: Vec<(u64, Registered, Responder<Unregistered>)>,
+    /// Registrations unregistered while their REGISTER was unanswered, by REGISTER request
+    /// id, with the id of the UNREGISTER to send once REGISTERED arrives.
+    pub(crate) unregistering: Vec<(u64, u64)>,
+    /// Invocations of async procedures, by request id, for the driver to spawn.
+    pub(crate) invocations: Vec<StartedInvocation>,
+    /// Tokens of the async invocations still running, cancelled by INTERRUPT.
+    pub(crate) interruptible: HashMap<u64, CancellationToken>,
+    pub(crate) joining: Option<JoinRequest>,
+    /// The last join, joined again after a reconnect.
+    pub(crate) rejoin: Option<Rejoin>,
+    pub(crate) message_queue: Vec<JsonValue>,
+    pub(crate) request_ids: RequestIds,
+    /// Waiting for the router's GOODBYE after `WampClient::leave`.
+    pub(crate) leaving: Option<oneshot::Sender<Goodbye>>,
+    /// Cancelled by `WampClient::close` to stop the session driver.
+    pub(crate) shutdown: CancellationToken,
+    /// Set when the client sent ABORT for a protocol violation, for the driver to end the session.
+    pub(crate) aborted: bool,
+    /// Set once the session is being left or closed, so a dropped transport is not reconnected.
+    pub(crate) closing: bool,
+    pub(crate) key_ring: Option<Arc<dyn KeyRing>>
 }
 
 impl CallbackHandler {
     pub(crate) fn merge(&mut self, handler: CallbackHandler) {
         self.callbacks.extend(handler.callbacks);
+        self.handler_lifecycles.extend(handler.handler_lifecycles);
+        self.pending_subscriptions.extend(handler.pending_subscriptions);
         self.subscriptions.extend(handler.subscriptions);
         self.on_subscribed.extend(handler.on_subscribed);
         self.call_results.extend(handler.call_results);
+        self.progressive_calls.extend(handler.progressive_calls);
+        self.sealed_calls.extend(handler.sealed_calls);
         self.publish_callbacks.extend(handler.publish_callbacks);
         self.unsubscribe_callbacks.extend(handler.unsubscribe_callbacks);
         self.register_callbacks.extend(handler.register_callbacks);
         self.unregistered_callbacks.extend(handler.unregistered_callbacks);
+        self.registrations.extend(handler.registrations);
+        self.invocations.extend(handler.invocations);
+        self.interruptible.extend(handler.interruptible);
+        if handler.joining.is_some() {
+            self.joining = handler.joining;
+        }
+        if handler.rejoin.is_some() {
+            self.rejoin = handler.rejoin;
+        }
+        if handler.key_ring.is_some() {
+            self.key_ring = handler.key_ring;
+        }
         self.message_queue.extend(handler.message_queue);
+        for (request, subscription, responder) in handler.unsubscribes {
+            let _ = self.remove_handler(request, &subscription, responder);
+        }
+        for (request, registered, responder) in handler.unregisters {
+            let _ = self.remove_registration(request, &registered, responder);
+        }
     }
 
     pub(crate) fn new() -> CallbackHandler {
         CallbackHandler { 
             callbacks: vec![], 
-            subscriptions: vec![],
+            handler_lifecycles: HashMap::new(),
+            pending_subscriptions: HashMap::new(),
+            subscriptions: HashMap::new(),
             on_subscribed: vec![],
             call_results: vec![], 
+            progressive_calls: vec![],
+            sealed_calls: HashMap::new(),
             publish_callbacks: vec![], 
             unsubscribe_callbacks: vec![], 
             register_callbacks: vec![], 
             unregistered_callbacks: vec![],
-            message_queue: vec![]
+            registrations: vec![],
+            unsubscribes: vec![],
+            unregisters: vec![],
+            unregistering: vec![],
+            invocations: vec![],
+            interruptible: HashMap::new(),
+            joining: None,
+            rejoin: None,
+            message_queue: vec![],
+            request_ids: RequestIds::default(),
+            leaving: None,
+            shutdown: CancellationToken::new(),
+            aborted: false,
+            closing: false,
+            key_ring: None
+        }
+    }
+
+    /// Context for handlers, allocating request ids from this session.
+    pub(crate) fn context(&self) -> Context {
+        let mut context = Context::new(self.request_ids.clone());
+        context.messages.key_ring = self.key_ring.clone();
+        context
+    }
+
+    /// Encrypts the payload of a message sent to `uri` when the key ring has a key for it.
+    fn seal(&self, uri: &str, options: &mut JsonValue, args: &mut JsonValue, kwargs: &mut JsonValue) -> Result<(), Error> {
+        match self.key_ring.as_ref().and_then(|ring| ring.key_for(uri)) {
+            Some(key) => ppt::seal(&key, options, args, kwargs),
+            None => Ok(())
         }
     }
 
     pub fn send<T: WampMessageTrait>(&mut self, message: T) -> Result<(), Error> {
-        self.message_queue.push(message.to_json().map_err(|e| Error::JsonError(e))?.to_string());
+        self.message_queue.push(message.to_json().map_err(Error::JsonError)?);
         Ok(())
     }
 
-    pub fn subscribe(&mut self, subscription: Subscribe, on_subscribed: Callback<Result<Subscribed, ErrorMessage>>, on_event: Callback<Event>) -> Result<(), Error> {
-        self.send(subscription.clone())?;
-        self.subscriptions.push((subscription.request, on_event, None));
-        self.on_subscribed.push((subscription.request, on_subscribed));
-        Ok(())
+    /// Queues HELLO for `realm`. Its details are built anew for every join, so that
+    /// authenticators announce fresh `authextra`, such as a WAMP-SCRAM nonce.
+    pub(crate) fn join(&mut self, realm: &str, roles: Roles, authenticators: Vec<Arc<dyn Authenticator>>) -> Result<oneshot::Receiver<Result<Welcome, Error>>, Error> {
+        let (responder, pending) = oneshot::channel();
+        self.send(Hello { realm: realm.to_string(), details: hello_details(&roles, &authenticators) })?;
+        self.rejoin = Some((realm.to_string(), roles, authenticators.clone()));
+        self.joining = Some((responder, authenticators));
+        Ok(pending)
+    }
+
+    /// Joins the realm of the last join again. Returns the realm being rejoined, or `None`
+    /// when the session never joined one.
+    pub(crate) fn rejoin(&mut self) -> Option<String> {
+        let (realm, roles, authenticators) = self.rejoin.clone()?;
+        self.join(&realm, roles, authenticators).ok()?;
+        Some(realm)
+    }
+
+    /// Queues SUBSCRIBE and REGISTER again for everything the application still holds,
+    /// reusing the original request ids in the new session.
+    pub(crate) fn restore(&mut self) {
+        let mut to_send = vec![];
+        for (_, subscription) in self.subscriptions.drain() {
+            self.pending_subscriptions.insert(subscription.subscribe.request, subscription);
+        }
+        // Entries whose handlers were all removed while they were being restored.
+        self.pending_subscriptions.retain(|_, subscription| !subscription.handlers.is_empty());
+        for subscription in self.pending_subscriptions.values() {
+            to_send.push(subscription.subscribe.to_json());
+        }
+        for registration in &mut self.registrations {
+            registration.2 = None;
+            to_send.push(registration.0.to_json());
+        }
+        self.message_queue.extend(to_send.into_iter().flatten());
     }
 
-    pub fn call(&mut self, call: Call, on_result: Callback<Result<MessageResult, ErrorMessage>>) -> Result<(), Error> {
+    /// Drops every request waiting for a reply, so their futures resolve with
+    /// `Error::SessionClosed`.
+    pub(crate) fn fail_pending(&mut self) {
+        self.fail_unrestored();
+        self.on_subscribed.clear();
+        self.register_callbacks.clear();
+    }
+
+    /// Fails the requests a reconnect cannot carry over. SUBSCRIBE and REGISTER are sent
+    /// again by `restore` with their original request ids, so their futures keep waiting
+    /// and resolve with the new session's reply.
+    pub(crate) fn fail_unrestored(&mut self) {
+        self.call_results.clear();
+        for (_, results) in self.progressive_calls.drain(..) {
+            let _ = results.send(Err(Error::SessionClosed));
+        }
+        self.sealed_calls.clear();
+        self.publish_callbacks.clear();
+        self.unsubscribe_callbacks.clear();
+        self.unregistered_callbacks.clear();
+        // Their REGISTER is not restored, so the registration went away with the session.
+        self.unregistering.clear();
+        self.joining = None;
+        self.leaving = None;
+        // Replies to invocations of the lost session could not reach their callers.
+        for (_, token) in self.interruptible.drain() {
+            token.cancel();
+        }
+    }
+
+    pub(crate) fn notify(&mut self, lifecycle: Lifecycle) {
+        let mut context = self.context();
+        for cb in self.handler_lifecycles.values() {
+            context = cb(context, lifecycle.clone());
+        }
+        for callback in &self.callbacks {
+            if let Events::Lifecycle(cb) = callback {
+                context = cb(context, lifecycle.clone());
+            }
+        }
+        self.merge(context.messages);
+    }
+
+    /// Queues GOODBYE with `reason`. The returned receiver gets the router's GOODBYE.
+    pub(crate) fn leave(&mut self, reason: &str) -> Result<oneshot::Receiver<Goodbye>, Error> {
+        let (responder, left) = oneshot::channel();
+        self.send(Goodbye { details: json::object! {}, reason: reason.to_string() })?;
+        self.leaving = Some(responder);
+        self.closing = true;
+        Ok(left)
+    }
+
+    /// Adds `on_event` as a handler for `topic`. A SUBSCRIBE is only sent when no other
+    /// handler is subscribed to the topic with the same match policy.
+    pub fn subscribe(&mut self, topic: &str, options: SubscribeOptions, on_event: Callback<ReceivedEvent>) -> Result<PendingSubscription, Error> {
+        let handler = next_handler();
+        let subscribed = if let Some((&id, subscription)) = self.subscriptions.iter_mut().find(|(_, i)| i.matches(topic, options.matching)) {
+            subscription.handlers.push((handler, on_event));
+            let (responder, subscribed) = PendingResponse::new(subscription.subscribe.request);
+            let _ = responder.send(Ok(Subscribed { request: subscription.subscribe.request, subscription: id }));
+            subscribed
+        } else if let Some((&pending, subscription)) = self.pending_subscriptions.iter_mut().find(|(_, i)| i.matches(topic, options.matching)) {
+            subscription.handlers.push((handler, on_event));
+            let (responder, subscribed) = PendingResponse::new(pending);
+            self.on_subscribed.push((pending, responder));
+            subscribed
+        } else {
+            let request = self.request_ids.next();
+            let (responder, subscribed) = PendingResponse::new(request);
+            let subscribe = Subscribe {
+                request,
+                options: options.to_json(),
+                topic: topic.to_string()
+            };
+            self.send(subscribe.clone())?;
+            self.pending_subscriptions.insert(request, TopicSubscription { subscribe, handlers: vec![(handler, on_event)] });
+            self.on_subscribed.push((request, responder));
+            subscribed
+        };
+
+        Ok(PendingSubscription { handler, topic: topic.to_string(), matching: options.matching, subscribed })
+    }
+
+    /// Router subscription the handler of `subscription` has in the current session. Resolves
+    /// once a restored subscription is acknowledged, and is `None` when the handler is gone.
+    pub(crate) fn resubscribed(&mut self, subscription: &Subscription) -> Option<PendingResponse<Subscribed>> {
+        if let Some((&id, shared)) = self.subscriptions.iter().find(|(_, i)| i.has_handler(subscription.handler)) {
+            let request = shared.subscribe.request;
+            let (responder, subscribed) = PendingResponse::new(request);
+            let _ = responder.send(Ok(Subscribed { request, subscription: id }));
+            return Some(subscribed);
+        }
+        let (&request, _) = self.pending_subscriptions.iter().find(|(_, i)| i.has_handler(subscription.handler))?;
+        let (responder, subscribed) = PendingResponse::new(request);
+        self.on_subscribed.push((request, responder));
+        Some(subscribed)
+    }
+
+    pub fn call(&mut self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: CallOptions) -> Result<PendingResponse<MessageResult>, Error> {
+        let call = self.sealed_call(procedure, args, kwargs, options.to_json())?;
+        let (responder, pending) = PendingResponse::new(call.request);
         self.send(call.clone())?;
-        self.call_results.push((call.request, on_result));        
-        Ok(())
+        self.call_results.push((call.request, responder));
+        Ok(pending)
     }
 
-    pub fn publish(&mut self, publish: Publish, on_published: Callback<Result<Published, ErrorMessage>>) -> Result<(), Error> {
+    fn sealed_call(&mut self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: JsonValue) -> Result<Call, Error> {
+        let mut call = Call { request: self.request_ids.next(), options, procedure: procedure.to_string(), args, kwargs };
+        self.seal(procedure, &mut call.options, &mut call.args, &mut call.kwargs)?;
+        if !call.options["ppt_scheme"].is_null() {
+            self.sealed_calls.insert(call.request, call.procedure.clone());
+        }
+        Ok(call)
+    }
+
+    /// Asks the router to cancel the call `request`. Its future still resolves with the
+    /// router's reply, normally a `wamp.error.canceled` ERROR.
+    pub fn cancel(&mut self, request: u64, mode: CancelMode) -> Result<(), Error> {
+        self.send(Cancel { request, options: json::object! { mode: mode.as_str() } })
+    }
+
+    /// Forgets the call `request`, so a reply arriving later is dropped.
+    pub(crate) fn abandon(&mut self, request: u64) {
+        self.call_results.retain(|i| i.0 != request);
+        self.progressive_calls.retain(|i| i.0 != request);
+        self.sealed_calls.remove(&request);
+    }
+
+    /// Calls with `receive_progress` set, so the callee can send partial results.
+    pub fn call_progressive(&mut self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: CallOptions) -> Result<ProgressiveResults, Error> {
+        let (results, receiver) = mpsc::unbounded_channel();
+        let mut options = options.to_json();
+        options["receive_progress"] = true.into();
+        let call = self.sealed_call(procedure, args, kwargs, options)?;
+        self.send(call.clone())?;
+        self.progressive_calls.push((call.request, results));
+        Ok(ProgressiveResults { request: call.request, receiver })
+    }
+
+    /// Publishes and, when `options` asks for an acknowledgement, returns a future
+    /// resolving to the router's PUBLISHED. Unacknowledged publications are not tracked.
+    pub fn publish(&mut self, topic: &str, args: JsonValue, kwargs: JsonValue, options: PublishOptions) -> Result<Option<PendingResponse<Published>>, Error> {
+        let mut publish = Publish { request: self.request_ids.next(), options: options.to_json(), topic: topic.to_string(), args, kwargs };
+        self.seal(topic, &mut publish.options, &mut publish.args, &mut publish.kwargs)?;
         self.send(publish.clone())?;
-        self.publish_callbacks.push((publish.request, on_published));
-        Ok(())
+        if !options.acknowledge {
+            return Ok(None);
+        }
+        let (responder, pending) = PendingResponse::new(publish.request);
+        self.publish_callbacks.push((publish.request, responder));
+        Ok(Some(pending))
     }
 
-    pub fn unsubscribe(&mut self, unsubscribe: Unsubscribe, on_unsubscribed: Callback<Result<Unsubscribed, ErrorMessage>>) -> Result<(), Error> {
-        self.subscriptions.retain(|i| {
-            if let Some(num) = i.2 {
-                if num == unsubscribe.subscription {
-                    return false
-                }
+    /// Removes the handler of `subscription`. The UNSUBSCRIBE is only sent once the last
+    /// handler sharing the router subscription is gone; until then this resolves right away.
+    pub fn unsubscribe(&mut self, subscription: &Subscription) -> Result<PendingResponse<Unsubscribed>, Error> {
+        let request = self.request_ids.next();
+        let (responder, pending) = PendingResponse::new(request);
+        self.remove_handler(request, subscription, responder)?;
+        Ok(pending)
+    }
+
+    /// Unsubscribes like `unsubscribe` once this context is merged into the session.
+    pub(crate) fn unsubscribe_on_merge(&mut self, subscription: &Subscription) -> PendingResponse<Unsubscribed> {
+        let request = self.request_ids.next();
+        let (responder, pending) = PendingResponse::new(request);
+        self.unsubscribes.push((request, subscription.clone(), responder));
+        pending
+    }
+
+    fn remove_handler(&mut self, request: u64, subscription: &Subscription, responder: Responder<Unsubscribed>) -> Result<(), Error> {
+        self.handler_lifecycles.remove(&subscription.handler);
+        let acknowledged = self.subscriptions.iter_mut().find(|(_, i)| i.has_handler(subscription.handler));
+        if let Some((&id, shared)) = acknowledged {
+            shared.handlers.retain(|i| i.0 != subscription.handler);
+            if shared.handlers.is_empty() {
+                self.subscriptions.remove(&id);
+                self.send(Unsubscribe { request, subscription: id })?;
+                self.unsubscribe_callbacks.push((request, responder));
+                return Ok(());
             }
-            true
-        });
-        self.send(unsubscribe.clone())?;
-        self.unsubscribe_callbacks.push((unsubscribe.request, on_unsubscribed));
+        }
+
+        // Handlers being restored after a reconnect wait for their SUBSCRIBED. Once the last
+        // one is gone, the entry is kept without handlers and unsubscribed when it arrives.
+        let restoring = self.pending_subscriptions.values_mut().find(|i| i.has_handler(subscription.handler));
+        if let Some(shared) = restoring {
+            shared.handlers.retain(|i| i.0 != subscription.handler);
+        }
+
+        let _ = responder.send(Ok(Unsubscribed { request }));
         Ok(())
     }
 
-    pub fn register(&mut self, register: Register, on_registered: Callback<Result<Registered, ErrorMessage>>) -> Result<(), Error> {
+    pub fn register(&mut self, uri: &str, options: RegisterOptions, procedure: Procedure) -> Result<PendingResponse<Registered>, Error> {
+        self.add_registration(uri, options, Callee::Sync(procedure))
+    }
+
+    pub(crate) fn register_async(&mut self, uri: &str, options: RegisterOptions, procedure: AsyncProcedure) -> Result<PendingResponse<Registered>, Error> {
+        self.add_registration(uri, options, Callee::Async(procedure))
+    }
+
+    pub(crate) fn register_streaming(&mut self, uri: &str, options: RegisterOptions, procedure: StreamingProcedure) -> Result<PendingResponse<Registered>, Error> {
+        self.add_registration(uri, options, Callee::Streaming(procedure))
+    }
+
+    fn add_registration(&mut self, uri: &str, options: RegisterOptions, callee: Callee) -> Result<PendingResponse<Registered>, Error> {
+        let register = Register { request: self.request_ids.next(), options: options.to_json(), procedure: uri.to_string() };
+        let (responder, pending) = PendingResponse::new(register.request);
         self.send(register.clone())?;
-        self.register_callbacks.push((register.request, on_registered));
-        Ok(())
+        self.register_callbacks.push((register.request, responder));
+        self.registrations.push((register, callee, None));
+        Ok(pending)
     }
 
-    pub fn unregister(&mut self, unregister: Unregister, on_unregistered: Callback<Result<Unregistered, ErrorMessage>>) -> Result<(), Error> {
-        self.send(unregister.clone())?;
-        self.unregistered_callbacks.push((unregister.request, on_unregistered));
-        Ok(())
+    /// Unregisters the procedure of `registered`, so it is no longer restored either. While
+    /// the session is restoring it, the UNREGISTER waits for the new REGISTERED.
+    pub fn unregister(&mut self, registered: &Registered) -> Result<PendingResponse<Unregistered>, Error> {
+        let request = self.request_ids.next();
+        let (responder, pending) = PendingResponse::new(request);
+        self.remove_registration(request, registered, responder)?;
+        Ok(pending)
+    }
+
+    /// Unregisters like `unregister` once this context is merged into the session.
+    pub(crate) fn unregister_on_merge(&mut self, registered: &Registered) -> PendingResponse<Unregistered> {
+        let request = self.request_ids.next();
+        let (responder, pending) = PendingResponse::new(request);
+        self.unregisters.push((request, Registered { request: registered.request, registration: registered.registration }, responder));
+        pending
+    }
+
+    fn remove_registration(&mut self, request: u64, registered: &Registered, responder: Responder<Unregistered>) -> Result<(), Error> {
+        self.unregistered_callbacks.push((request, responder));
+        let index = self.registrations.iter().position(|i| i.0.request == registered.request);
+        match index.map(|index| self.registrations.remove(index).2) {
+            Some(None) => {
+                self.unregistering.push((registered.request, request));
+                Ok(())
+            },
+            Some(Some(registration)) => self.send(Unregister { request, registration }),
+            None => self.send(Unregister { request, registration: registered.registration })
+        }
     }
 
     pub fn on(&mut self, callback: Events) {
         self.callbacks.push(callback);
     }
-}
\ No newline at end of file
+
+    pub(crate) fn handle_event(&mut self, mut event: WampEvents, context: Context) -> Context {
+        if let Err(error) = self.open_payload(&mut event) {
+            return self.reject_payload(event, error, context);
+        }
+
+        match event {
+            WampEvents::Welcome(welcome) => {
+                if let Some((responder, _)) = self.joining.take() {
+                    let _ = responder.send(Ok(welcome.clone()));
+                }
+
+                for callback in &self.callbacks {
+                    if let Events::Welcome(cb) = callback {
+                        return cb(context, welcome.clone());
+                    }
+                };
+            },
+            WampEvents::Abort(abort) => {
+                if let Some((responder, _)) = self.joining.take() {
+                    let _ = responder.send(Err(Error::Abort { reason: abort.reason.clone(), details: abort.details.clone() }));
+                }
+
+                for callback in &self.callbacks {
+                    if let Events::Abort(cb) = callback {
+                        return cb(context, abort.clone());
+                    }
+                };
+            },
+            WampEvents::Challenge(challenge) => {
+                let answer = self.joining.as_ref().map(|(_, authenticators)| {
+                    authenticators.iter()
+                        .find(|authenticator| authenticator.authmethod() == challenge.authmethod)
+                        .map(|authenticator| authenticator.authenticate(&challenge.extra))
+                        .unwrap_or_else(|| Err(Error::Authentication(format!("no authenticator for {}", challenge.authmethod))))
+                });
+                if let Some(answer) = answer {
+                    let mut context = context;
+                    match answer {
+                        Ok(authenticate) => {
+                            let _ = context.send(authenticate);
+                        },
+                        Err(error) => {
+                            let _ = context.send(Abort {
+                                details: json::object! { message: error.to_string() },
+                                reason: "wamp.error.cannot_authenticate".to_string()
+                            });
+                            if let Some((responder, _)) = self.joining.take() {
+                                let _ = responder.send(Err(error));
+                            }
+                        }
+                    }
+                    return context;
+                }
+
+                for callback in &self.callbacks {
+                    if let Events::Challenge(cb) = callback {
+                        return cb(context, challenge);
+                    }
+                }
+            },
+            WampEvents::Goodbye(goodbye) => {
+                let mut context = context;
+                match self.leaving.take() {
+                    Some(responder) => {
+                        let _ = responder.send(goodbye.clone());
+                    },
+                    // The router closed the session, which the client has to acknowledge.
+                    None => {
+                        let _ = context.send(Goodbye { details: json::object! {}, reason: "wamp.close.goodbye_and_out".to_string() });
+                    }
+                }
+
+                for callback in &self.callbacks {
+                    if let Events::Goodbye(cb) = callback {
+                        return cb(context, goodbye);
+                    }
+                }
+                return context;
+            },
+            WampEvents::ErrorMessage(error) => {
+
+                match error.request_type {
+
+                    Subscribe::ID => {
+                        self.pending_subscriptions.remove(&error.request);
+                        resolve_all(&mut self.on_subscribed, error.request, Err(error));
+                    },
+
+                    Call::ID => {
+                        self.sealed_calls.remove(&error.request);
+                        if let Some(index) = self.progressive_calls.iter().position(|i| i.0 == error.request) {
+                            let (_, results) = self.progressive_calls.remove(index);
+                            let _ = results.send(Err(error.into()));
+                        } else {
+                            resolve(&mut self.call_results, error.request, Err(error));
+                        }
+                    },
+
+                    Publish::ID => resolve(&mut self.publish_callbacks, error.request, Err(error)),
+
+                    Unsubscribe::ID => resolve(&mut self.unsubscribe_callbacks, error.request, Err(error)),
+
+                    Register::ID => {
+                        // A refused procedure must not be registered again by `restore`.
+                        self.registrations.retain(|i| i.0.request != error.request);
+                        if let Some(index) = self.unregistering.iter().position(|i| i.0 == error.request) {
+                            let (_, request) = self.unregistering.remove(index);
+                            resolve(&mut self.unregistered_callbacks, request, Ok(Unregistered { request }));
+                        }
+                        resolve(&mut self.register_callbacks, error.request, Err(error));
+                    },
+
+                    Unregister::ID => resolve(&mut self.unregistered_callbacks, error.request, Err(error)),
+
+                    _ => {
+                        for callback in &self.callbacks {
+                            if let Events::Error(cb) = callback {
+                                return cb(context, error.clone());
+                            }
+                        }
+                    }
+                }
+            },
+            WampEvents::Published(published) => {
+                resolve(&mut self.publish_callbacks, published.request, Ok(published));
+            },
+            WampEvents::Subscribed(subscribed) => {
+                if let Some(subscription) = self.pending_subscriptions.remove(&subscribed.request) {
+                    match self.subscriptions.entry(subscribed.subscription) {
+                        Entry::Vacant(_) if subscription.handlers.is_empty() => {
+                            let request = self.request_ids.next();
+                            let _ = self.send(Unsubscribe { request, subscription: subscribed.subscription });
+                        },
+                        Entry::Occupied(mut shared) => shared.get_mut().handlers.extend(subscription.handlers),
+                        Entry::Vacant(vacant) => {
+                            vacant.insert(subscription);
+                        }
+                    }
+                }
+
+                resolve_all(&mut self.on_subscribed, subscribed.request, Ok(subscribed));
+            },
+            WampEvents::Unsubscribed(unsubscribed) => {
+                resolve(&mut self.unsubscribe_callbacks, unsubscribed.request, Ok(unsubscribed));
+            },
+            WampEvents::Event(event) => {
+                if let Some(subscription) = self.subscriptions.get(&event.subscription) {
+                    let topic = match Match::from_options(&subscription.subscribe.options) {
+                        Match::Exact => subscription.subscribe.topic.clone(),
+                        _ => event.details["topic"].as_str().unwrap_or(&subscription.subscribe.topic).to_string()
+                    };
+                    let received = ReceivedEvent::new(topic, event);
+                    let mut context = context;
+                    for (_, on_event) in &subscription.handlers {
+                        context = on_event(context, received.clone());
+                    }
+                    return context;
+                }
+            },
+            WampEvents::MessageResult(result) => {
+                if result.details["progress"].as_bool() != Some(true) {
+                    self.sealed_calls.remove(&result.request);
+                }
+                if let Some(index) = self.progressive_calls.iter().position(|i| i.0 == result.request) {
+                    let last = result.details["progress"].as_bool() != Some(true);
+                    let _ = self.progressive_calls[index].1.send(Ok(result));
+                    if last {
+                        self.progressive_calls.remove(index);
+                    }
+                } else {
+                    resolve(&mut self.call_results, result.request, Ok(result));
+                }
+            },
+            WampEvents::Registered(registered) => {
+                for registration in &mut self.registrations {
+                    if registration.0.request == registered.request {
+                        registration.2 = Some(registered.registration);
+                    }
+                }
+                if let Some(index) = self.unregistering.iter().position(|i| i.0 == registered.request) {
+                    let (_, request) = self.unregistering.remove(index);
+                    let _ = self.send(Unregister { request, registration: registered.registration });
+                }
+
+                resolve(&mut self.register_callbacks, registered.request, Ok(registered));
+            },
+            WampEvents::Unregistered(unregistered) => {
+                resolve(&mut self.unregistered_callbacks, unregistered.request, Ok(unregistered));
+            },
+            WampEvents::Invocation(mut invocation) => {
+                let request = invocation.request;
+                for registration in &self.registrations {
+                    if registration.2 == Some(invocation.registration) {
+                        // Routers only send the procedure for pattern registrations; fill it in
+                        // so handlers can always read it from `details.procedure`.
+                        if invocation.details["procedure"].is_null() {
+                            invocation.details["procedure"] = registration.0.procedure.clone().into();
+                        }
+                        let procedure_uri = invocation.details["procedure"].as_str().unwrap_or_default();
+                        let key = self.key_ring.as_ref().and_then(|ring| ring.key_for(procedure_uri));
+                        match &registration.1 {
+                            Callee::Sync(procedure) => {
+                                let (mut context, result) = procedure(context, invocation);
+                                context.messages.message_queue.extend(invocation_reply(request, result, false, key.as_ref()));
+                                return context;
+                            },
+                            Callee::Async(procedure) => {
+                                let running = Running::Future(procedure(invocation));
+                                self.start_invocation(request, running, key);
+                                return context;
+                            },
+                            Callee::Streaming(procedure) => {
+                                let progress = invocation.details["receive_progress"].as_bool() == Some(true);
+                                let running = Running::Stream { progress, results: procedure(invocation) };
+                                self.start_invocation(request, running, key);
+                                return context;
+                            }
+                        }
+                    }
+                }
+
+                let mut context = context;
+                let _ = context.send(ErrorMessage {
+                    request_type: Invocation::ID,
+                    request,
+                    details: json::object! {},
+                    error: "wamp.error.no_such_registration".to_string(),
+                    args: JsonValue::Null,
+                    kwargs: JsonValue::Null
+                });
+                return context;
+            },
+            WampEvents::Interrupt(interrupt) => {
+                if let Some(token) = self.interruptible.remove(&interrupt.request) {
+                    token.cancel();
+                    // With `killnowait` the router has already answered the caller.
+                    if interrupt.options["mode"].as_str() != Some(CancelMode::KillNoWait.as_str()) {
+                        let _ = self.send(ErrorMessage {
+                            request_type: Invocation::ID,
+                            request: interrupt.request,
+                            details: json::object! {},
+                            error: "wamp.error.canceled".to_string(),
+                            args: JsonValue::Null,
+                            kwargs: JsonValue::Null
+                        });
+                    }
+                }
+
+                for callback in &self.callbacks {
+                    if let Events::Interrupt(cb) = callback {
+                        return cb(context, interrupt);
+                    }
+                }
+            },
+            WampEvents::Yield(yield_frame) => {
+                for callback in &self.callbacks {
+                    if let Events::Yield(cb) = callback {
+                        return cb(context, yield_frame);
+                    }    
+                }
+            },
+            other => {
+                let mut context = context;
+                let _ = context.send(Abort {
+                    details: json::object! { message: "unexpected message for a client session" },
+                    reason: "wamp.error.protocol_violation".to_string()
+                });
+                self.report(Error::Protocol(format!("unexpected message {other:?}")));
+                self.aborted = true;
+                return context;
+            }
+        } 
+        context
+    }
+
+    fn start_invocation(&mut self, request: u64, running: Running, key: Option<PayloadKey>) {
+        let interrupted = CancellationToken::new();
+        self.interruptible.insert(request, interrupted.clone());
+        self.invocations.push(StartedInvocation { request, interrupted, running, key });
+    }
+
+    /// Decrypts the payload of an EVENT, RESULT or INVOCATION encrypted end to end.
+    fn open_payload(&self, event: &mut WampEvents) -> Result<(), Error> {
+        if let Some(uri) = self.unsealed_uri(event) {
+            return Err(Error::Encryption(format!("payload for {uri} is not encrypted")));
+        }
+        let key_ring = self.key_ring.as_deref();
+        match event {
+            WampEvents::Event(event) => ppt::open(key_ring, &event.details, &mut event.args, &mut event.kwargs),
+            WampEvents::MessageResult(result) => ppt::open(key_ring, &result.details, &mut result.args, &mut result.kwargs),
+            WampEvents::Invocation(invocation) => ppt::open(key_ring, &invocation.details, &mut invocation.args, &mut invocation.kwargs),
+            WampEvents::ErrorMessage(error) => ppt::open(key_ring, &error.details, &mut error.args, &mut error.kwargs),
+            _ => Ok(())
+        }
+    }
+
+    /// URI of an EVENT, INVOCATION or RESULT that arrived unencrypted although the key ring
+    /// has a key for it, and so should have been sealed by its sender.
+    fn unsealed_uri(&self, event: &WampEvents) -> Option<String> {
+        let key_ring = self.key_ring.as_deref()?;
+        let (details, uri) = match event {
+            WampEvents::Event(event) => {
+                let topic = self.subscriptions.get(&event.subscription).map(|i| i.subscribe.topic.clone());
+                (&event.details, event.details["topic"].as_str().map(str::to_string).or(topic)?)
+            },
+            WampEvents::Invocation(invocation) => {
+                let procedure = self.registrations.iter()
+                    .find(|i| i.2 == Some(invocation.registration))
+                    .map(|i| i.0.procedure.clone());
+                (&invocation.details, invocation.details["procedure"].as_str().map(str::to_string).or(procedure)?)
+            },
+            WampEvents::MessageResult(result) => (&result.details, self.sealed_calls.get(&result.request)?.clone()),
+            _ => return None
+        };
+        (details["ppt_scheme"].is_null() && key_ring.key_for(&uri).is_some()).then_some(uri)
+    }
+
+    /// Handles a message whose payload could not be decrypted or should have been
+    /// encrypted. The call of a RESULT fails,
+    /// as does the request of an ERROR, an INVOCATION is answered with an ERROR, and an
+    /// EVENT is reported through `Events::Failure`.
+    fn reject_payload(&mut self, event: WampEvents, error: Error, context: Context) -> Context {
+        let failure = |request_type, request| ErrorMessage {
+            request_type,
+            request,
+            details: json::object! {},
+            error: "wamp.error.invalid_payload".to_string(),
+            args: json::array![error.to_string()],
+            kwargs: JsonValue::Null
+        };
+        match event {
+            WampEvents::MessageResult(result) => {
+                let failed = failure(Call::ID, result.request);
+                self.handle_event(WampEvents::ErrorMessage(failed), context)
+            },
+            WampEvents::ErrorMessage(error) => {
+                let failed = failure(error.request_type, error.request);
+                self.handle_event(WampEvents::ErrorMessage(failed), context)
+            },
+            WampEvents::Invocation(invocation) => {
+                let mut context = context;
+                let _ = context.send(failure(Invocation::ID, invocation.request));
+                context
+            },
+            _ => {
+                self.report(error);
+                context
+            }
+        }
+    }
+
+    /// Hands an error raised while receiving to the first `Events::Failure` callback.
+    pub(crate) fn report(&mut self, error: Error) {
+        let context = self.callbacks.iter()
+            .find_map(|callback| match callback {
+                Events::Failure(cb) => Some(cb),
+                _ => None
+            })
+            .map(|cb| cb(self.context(), error));
+        if let Some(context) = context {
+            self.merge(context.messages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::WampScram;
+
+    fn ignore() -> Callback<ReceivedEvent> {
+        Box::new(|context, _| context)
+    }
+
+    fn record(received: &Arc<Mutex<Vec<String>>>, name: &'static str) -> Callback<ReceivedEvent> {
+        let received = received.clone();
+        Box::new(move |context, event| {
+            received.lock().unwrap().push(format!("{name} {}", event.topic));
+            context
+        })
+    }
+
+    /// Hands a raw router message to the handler the way the session driver does.
+    fn deliver(handler: &mut CallbackHandler, message: &str) {
+        let event = WampEvents::parse_message(message).unwrap();
+        let context = handler.context();
+        let context = handler.handle_event(event, context);
+        handler.merge(context.messages);
+    }
+
+    /// Messages the client queued for the router.
+    fn sent(handler: &mut CallbackHandler) -> Vec<JsonValue> {
+        std::mem::take(&mut handler.message_queue)
+    }
+
+    #[tokio::test]
+    async fn handlers_share_one_router_subscription() {
+        let mut handler = CallbackHandler::new();
+        let first = handler.subscribe("com.example", SubscribeOptions::prefix(), ignore()).unwrap();
+        let second = handler.subscribe("com.example", SubscribeOptions::prefix(), ignore()).unwrap();
+        assert_eq!(handler.message_queue.len(), 1);
+
+        handler.handle_event(WampEvents::Subscribed(Subscribed { request: 1, subscription: 7 }), handler.context());
+        let (first, second) = (first.await.unwrap(), second.await.unwrap());
+        assert_eq!((first.subscription, second.subscription), (7, 7));
+
+        handler.unsubscribe(&first).unwrap().await.unwrap();
+        assert_eq!(handler.message_queue.len(), 1);
+        let _unsubscribed = handler.unsubscribe(&second).unwrap();
+        assert_eq!(handler.message_queue.len(), 2);
+        assert!(handler.subscriptions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn events_reach_the_handler_of_their_own_subscription() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut handler = CallbackHandler::new();
+        let chats = handler.subscribe("com.example.chats", SubscribeOptions::default(), record(&received, "chats")).unwrap();
+        let users = handler.subscribe("com.example.users", SubscribeOptions::prefix(), record(&received, "users")).unwrap();
+        assert_eq!(sent(&mut handler).len(), 2);
+
+        deliver(&mut handler, "[33, 2, 200]");
+        deliver(&mut handler, "[33, 1, 100]");
+        assert_eq!(chats.await.unwrap().subscription, 100);
+        assert_eq!(users.await.unwrap().subscription, 200);
+
+        deliver(&mut handler, "[36, 100, 1, {}]");
+        deliver(&mut handler, r#"[36, 200, 2, {"topic": "com.example.users.joe"}]"#);
+        deliver(&mut handler, "[36, 100, 3, {}]");
+        assert_eq!(*received.lock().unwrap(), vec![
+            "chats com.example.chats",
+            "users com.example.users.joe",
+            "chats com.example.chats"
+        ]);
+    }
+
+    #[test]
+    fn events_expose_the_disclosed_publisher() {
+        let WampEvents::Event(event) = WampEvents::parse_message(r#"[36, 1, 2, {"publisher": 9, "publisher_authid": "joe", "trustlevel": 2}]"#).unwrap() else {
+            panic!("expected an EVENT");
+        };
+        let received = ReceivedEvent::new("com.example".to_string(), event);
+        assert_eq!(
+            (received.publisher, received.publisher_authid.as_deref(), received.publisher_authrole, received.trustlevel),
+            (Some(9), Some("joe"), None, Some(2))
+        );
+    }
+
+    fn register(handler: &mut CallbackHandler, procedure: &str) -> PendingResponse<Registered> {
+        handler.register(procedure, RegisterOptions::default(), Box::new(|context, invocation| {
+            let result = match invocation.args[0].as_i64() {
+                Some(number) => Ok(Payload::new(json::array![number * 2], JsonValue::Null)),
+                None => Err(ProcedureError::new("wamp.error.invalid_argument"))
+            };
+            (context, result)
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sync_procedures_answer_invocations() {
+        let mut handler = CallbackHandler::new();
+        let registered = register(&mut handler, "com.example.double");
+        deliver(&mut handler, "[65, 1, 100]");
+        assert_eq!(registered.await.unwrap().registration, 100);
+        sent(&mut handler);
+
+        deliver(&mut handler, "[68, 7, 100, {}, [21]]");
+        assert_eq!(sent(&mut handler), vec![json::array![70, 7, {}, [42]]]);
+        deliver(&mut handler, r#"[68, 8, 100, {}, ["x"]]"#);
+        assert_eq!(sent(&mut handler), vec![json::array![8, 68, 8, {}, "wamp.error.invalid_argument"]]);
+        deliver(&mut handler, "[68, 9, 300, {}, [1]]");
+        assert_eq!(sent(&mut handler), vec![json::array![8, 68, 9, {}, "wamp.error.no_such_registration"]]);
+    }
+
+    #[tokio::test]
+    async fn refused_registrations_are_not_restored() {
+        let mut handler = CallbackHandler::new();
+        let refused = register(&mut handler, "com.example.double");
+        deliver(&mut handler, r#"[8, 64, 1, {}, "wamp.error.procedure_already_exists"]"#);
+        assert!(matches!(refused.await, Err(Error::Wamp(_))));
+        assert!(handler.registrations.is_empty());
+
+        sent(&mut handler);
+        handler.restore();
+        assert!(sent(&mut handler).is_empty());
+    }
+
+    #[tokio::test]
+    async fn registrations_unregistered_from_a_handler_are_not_restored() {
+        let mut handler = CallbackHandler::new();
+        let registered = register(&mut handler, "com.example.double");
+        deliver(&mut handler, "[65, 1, 100]");
+        let registered = registered.await.unwrap();
+        sent(&mut handler);
+
+        let mut context = handler.context();
+        let unregistered = context.unregister(&registered).unwrap();
+        handler.merge(context.messages);
+        assert_eq!(sent(&mut handler), vec![json::array![66, 2, 100]]);
+        deliver(&mut handler, "[67, 2]");
+        unregistered.await.unwrap();
+
+        handler.restore();
+        assert!(sent(&mut handler).is_empty());
+    }
+
+    #[test]
+    fn rejoining_announces_a_fresh_scram_nonce() {
+        let mut handler = CallbackHandler::new();
+        let _joined = handler.join("realm1", Roles::default(), vec![Arc::new(WampScram::new("user", "pencil"))]).unwrap();
+        assert_eq!(handler.rejoin(), Some("realm1".to_string()));
+
+        let hellos = sent(&mut handler);
+        let nonce = |hello: &JsonValue| hello[2]["authextra"]["nonce"].as_str().unwrap().to_string();
+        assert_eq!((hellos.len(), hellos[1][1].as_str()), (2, Some("realm1")));
+        assert_ne!(nonce(&hellos[0]), nonce(&hellos[1]));
+    }
+
+    #[tokio::test]
+    async fn restored_subscriptions_take_their_new_ids() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut handler = CallbackHandler::new();
+        let chats = handler.subscribe("com.example.chats", SubscribeOptions::default(), record(&received, "chats")).unwrap();
+        let users = handler.subscribe("com.example.users", SubscribeOptions::default(), record(&received, "users")).unwrap();
+        deliver(&mut handler, "[33, 1, 100]");
+        deliver(&mut handler, "[33, 2, 200]");
+        let (_chats, users) = (chats.await.unwrap(), users.await.unwrap());
+        sent(&mut handler);
+
+        handler.restore();
+        let resubscribed = sent(&mut handler);
+        assert_eq!(resubscribed.len(), 2);
+        assert!(resubscribed.iter().all(|m| m[0] == Subscribe::ID));
+
+        deliver(&mut handler, "[33, 2, 300]");
+        deliver(&mut handler, "[33, 1, 400]");
+        deliver(&mut handler, "[36, 100, 1, {}]");
+        deliver(&mut handler, "[36, 300, 2, {}]");
+        deliver(&mut handler, "[36, 400, 3, {}]");
+        assert_eq!(*received.lock().unwrap(), vec!["users com.example.users", "chats com.example.chats"]);
+
+        let _unsubscribed = handler.unsubscribe(&users).unwrap();
+        assert_eq!(sent(&mut handler), vec![json::array![Unsubscribe::ID, 3, 300]]);
+    }
+
+    #[tokio::test]
+    async fn handlers_removed_while_restoring_are_unsubscribed() {
+        let mut handler = CallbackHandler::new();
+        let chats = handler.subscribe("com.example.chats", SubscribeOptions::default(), ignore()).unwrap();
+        deliver(&mut handler, "[33, 1, 100]");
+        let chats = chats.await.unwrap();
+
+        handler.restore();
+        sent(&mut handler);
+        handler.unsubscribe(&chats).unwrap().await.unwrap();
+        assert!(sent(&mut handler).is_empty());
+
+        deliver(&mut handler, "[33, 1, 300]");
+        assert_eq!(sent(&mut handler), vec![json::array![Unsubscribe::ID, 3, 300]]);
+        assert!(handler.subscriptions.is_empty() && handler.pending_subscriptions.is_empty());
+    }
+}
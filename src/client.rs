@@ -1,376 +1,1363 @@
-use websockets::{WebSocketBuilder, WebSocketError, WebSocket};
+use std::{collections::VecDeque, future::Future, sync::{Arc, Mutex, MutexGuard, PoisonError, Weak}, time::Duration};
+
+use futures::{Stream, StreamExt};
+use json::JsonValue;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{mpsc, watch};
+use websockets::WebSocketBuilder;
 use wamp_helpers::messages::{
-    Event, 
-    Subscribed, 
-    Subscribe, 
     WampMessageTrait, 
-    Call, 
     MessageResult, 
-    Publish, 
     Published, 
-    Unsubscribe, 
     Unsubscribed, 
-    Register, 
     Registered, 
-    Unregister, 
     Unregistered,
-    Events as WampEvents, 
-    ErrorMessage
+    Invocation,
+    Goodbye
+};
+
+use crate::{
+    auth::Authenticator,
+    error::Error,
+    meta::Meta,
+    callback::{CallbackHandler, Callback, Events, InvocationResult, Procedure, PendingResponse, PendingSubscription, ProgressiveResults, ReceivedEvent, Subscription, lock},
+    driver::{Connector, SessionDriver},
+    options::{CallOptions, CancelMode, History, InvokePolicy, PublishOptions, RegisterOptions, SubscribeOptions},
+    ppt::KeyRing,
+    rawsocket::{RawSocketTransport, MAX_MESSAGE_LENGTH},
+    reconnect::{Lifecycle, ReconnectPolicy},
+    serializer::Serializer,
+    service::{ProcedureDefinition, Service},
+    session::{RequestIds, Roles, SessionDetails},
+    typed::{from_payload, to_payload},
+    transport::{Transport, WebSocketTransport}
 };
 
-use crate::{error::Error, callback::{CallbackHandler, Callback, Events}};
+/// How long `WampClient::leave` waits for the router's GOODBYE.
+pub const LEAVE_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Lets handlers act on the session that called them. Whatever a handler does through it
+/// takes effect, and is sent, once the handler returns its context.
 pub struct Context {
-    messages: CallbackHandler
+    pub(crate) messages: CallbackHandler
 }
 
 impl Context {
+    pub(crate) fn new(request_ids: RequestIds) -> Context {
+        let mut messages = CallbackHandler::new();
+        messages.request_ids = request_ids;
+        Context { messages }
+    }
+
     pub fn send<T: WampMessageTrait>(&mut self, message: T) -> Result<(), Error> {
         self.messages.send(message)
     }
 
-    pub fn subscribe(&mut self, subscription: Subscribe, on_subscribed: Callback<Result<Subscribed, ErrorMessage>>, on_event: Callback<Event>) -> Result<(), Error> {
-        self.messages.subscribe(subscription, on_subscribed, on_event)
+    pub fn subscribe(&mut self, topic: &str, options: SubscribeOptions, on_event: Callback<ReceivedEvent>) -> Result<PendingSubscription, Error> {
+        self.messages.subscribe(topic, options, on_event)
+    }
+
+    pub fn call(&mut self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: CallOptions) -> Result<PendingResponse<MessageResult>, Error> {
+        self.messages.call(procedure, args, kwargs, options)
+    }
+
+    pub fn call_progressive(&mut self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: CallOptions) -> Result<ProgressiveResults, Error> {
+        self.messages.call_progressive(procedure, args, kwargs, options)
     }
 
-    pub fn call(&mut self, call: Call, on_result: Callback<Result<MessageResult, ErrorMessage>>) -> Result<(), Error> { 
-        self.messages.call(call, on_result)
+    pub fn cancel(&mut self, request: u64, mode: CancelMode) -> Result<(), Error> {
+        self.messages.cancel(request, mode)
     }
 
-    pub fn publish(&mut self, publish: Publish, on_published: Callback<Result<Published, ErrorMessage>>) -> Result<(), Error> {
-        self.messages.publish(publish, on_published)
+    pub fn publish(&mut self, topic: &str, args: JsonValue, kwargs: JsonValue, options: PublishOptions) -> Result<Option<PendingResponse<Published>>, Error> {
+        self.messages.publish(topic, args, kwargs, options)
     }
 
-    pub fn unsubscribe(&mut self, unsubscribe: Unsubscribe, on_unsubscribed: Callback<Result<Unsubscribed, ErrorMessage>>) -> Result<(), Error> {
-    
-        self.messages.unsubscribe(unsubscribe, on_unsubscribed)
+    /// Removes the handler of `subscription` once the handler returns this context.
+    pub fn unsubscribe(&mut self, subscription: &Subscription) -> Result<PendingResponse<Unsubscribed>, Error> {
+        Ok(self.messages.unsubscribe_on_merge(subscription))
     }
 
-    pub fn register(&mut self, register: Register, on_registered: Callback<Result<Registered, ErrorMessage>>) -> Result<(), Error> {
-        self.messages.register(register, on_registered)
+    pub fn register(&mut self, uri: &str, options: RegisterOptions, procedure: Procedure) -> Result<PendingResponse<Registered>, Error> {
+        self.messages.register(uri, options, procedure)
     }
 
-    pub fn unregister(&mut self, unregister: Unregister, on_unregistered: Callback<Result<Unregistered, ErrorMessage>>) -> Result<(), Error> {
-        self.messages.unregister(unregister, on_unregistered)
+    /// Unregisters the procedure of `registered` once the handler returns this context.
+    pub fn unregister(&mut self, registered: &Registered) -> Result<PendingResponse<Unregistered>, Error> {
+        Ok(self.messages.unregister_on_merge(registered))
     }
 
     pub fn on(&mut self, callback: Events) {
         self.messages.on(callback)
     }
+
+    pub fn set_key_ring<K: KeyRing + 'static>(&mut self, key_ring: K) {
+        self.messages.key_ring = Some(Arc::new(key_ring));
+    }
 }
 
+/// Handle to a WAMP session. Clones share the same session, so any number of tasks can
+/// send through it while the session driver reads from the socket in the background.
+///
+/// Request ids are assigned by the session, which numbers its requests sequentially.
+///
+/// Handlers run while the session is locked, so a handler must not call the methods that
+/// act right away, `on`, `set_key_ring`, `cancel` and `call_progressive`: they wait for
+/// that lock and deadlock the session. Handlers use the same methods of their `Context`
+/// instead, and leave awaiting the async methods to a spawned task.
+#[derive(Clone)]
 pub struct WampClient {
-    websocket: WebSocket,
-    handler: CallbackHandler
+    handler: Arc<Mutex<CallbackHandler>>,
+    outgoing: mpsc::UnboundedSender<JsonValue>,
+    closed: watch::Receiver<bool>
 }
 
 
 impl WampClient {
 
-    pub fn on(&mut self, callback: Events) {
-        self.handler.callbacks.push(callback);
+    pub fn on(&self, callback: Events) {
+        lock(&self.handler).on(callback);
     }
 
-    pub async fn subscribe(&mut self, subscription: Subscribe, on_subscribed: Callback<Result<Subscribed, ErrorMessage>>, on_event: Callback<Event>) -> Result<(), Error> {
-        self.send(subscription.clone()).await?;
-        self.handler.subscriptions.push((subscription.request, on_event, None));
-        self.handler.on_subscribed.push((subscription.request, on_subscribed));
-        Ok(())
+    /// Encrypts the payloads of calls, publications and results end to end for every URI
+    /// `key_ring` has a key for. Payloads received encrypted are decrypted before they
+    /// reach handlers, and unencrypted events, invocations and results for those URIs are
+    /// rejected like payloads that fail to decrypt.
+    pub fn set_key_ring<K: KeyRing + 'static>(&self, key_ring: K) {
+        lock(&self.handler).key_ring = Some(Arc::new(key_ring));
     }
 
-    pub async fn call(&mut self, call: Call, on_result: Callback<Result<MessageResult, ErrorMessage>>) -> Result<(), Error> {
-        self.send(call.clone()).await?;
-        self.handler.call_results.push((call.request, on_result));
-        Ok(())
+    /// Typed client for the router meta API of this session.
+    pub fn meta(&self) -> Meta {
+        Meta::new(self.clone())
     }
 
-    pub async fn publish(&mut self, publish: Publish, on_published: Callback<Result<Published, ErrorMessage>>) -> Result<(), Error> {
-        self.send(publish.clone()).await?;
-        self.handler.publish_callbacks.push((publish.request, on_published));
-        Ok(())
+    /// Joins `realm`, answering any CHALLENGE with `authenticator`. Fails with
+    /// `Error::Abort` when the router aborts the session.
+    pub async fn join<A: Authenticator + 'static>(&self, realm: &str, roles: Roles, authenticator: A) -> Result<SessionDetails, Error> {
+        self.join_with_methods(realm, roles, vec![Arc::new(authenticator)]).await
     }
 
-    pub async fn unsubscribe(&mut self, unsubscribe: Unsubscribe, on_unsubscribed: Callback<Result<Unsubscribed, ErrorMessage>>) -> Result<(), Error> {
-        self.handler.unsubscribe(unsubscribe, on_unsubscribed)
+    /// Joins `realm` announcing every method in `authenticators`. The router's CHALLENGE
+    /// is answered by the authenticator with the challenged `authmethod`.
+    pub async fn join_with_methods(&self, realm: &str, roles: Roles, authenticators: Vec<Arc<dyn Authenticator>>) -> Result<SessionDetails, Error> {
+        let welcome = self.request(|handler| handler.join(realm, roles, authenticators))?
+            .await
+            .map_err(|_| Error::SessionClosed)??;
+        Ok(SessionDetails::new(realm, welcome))
     }
 
-    pub async fn register(&mut self, register: Register, on_registered: Callback<Result<Registered, ErrorMessage>>) -> Result<(), Error> {
-        self.send(register.clone()).await?;
-        self.handler.register_callbacks.push((register.request, on_registered));
-        Ok(())
+    /// Subscribes `on_event` to `topic`. With a prefix or wildcard match, the handler gets
+    /// the concrete topic of every event in `ReceivedEvent::topic`.
+    pub async fn subscribe(&self, topic: &str, options: SubscribeOptions, on_event: Callback<ReceivedEvent>) -> Result<Subscription, Error> {
+        self.request(|handler| handler.subscribe(topic, options, on_event))?.await
     }
 
-    pub async fn unregister(&mut self, unregister: Unregister, on_unregistered: Callback<Result<Unregistered, ErrorMessage>>) -> Result<(), Error> {
-        self.send(unregister.clone()).await?;
-        self.handler.unregistered_callbacks.push((unregister.request, on_unregistered));
-        Ok(())
+    /// Calls `procedure` with positional `args` and keyword `kwargs`, either of which may
+    /// be `JsonValue::Null`.
+    pub async fn call(&self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: CallOptions) -> Result<MessageResult, Error> {
+        self.request(|handler| handler.call(procedure, args, kwargs, options))?.await
     }
 
-    pub async fn connect(url: &str, options: &mut WebSocketBuilder) -> Result<Self, WebSocketError> {
-        let websocket = options.connect(url).await?;
-        
-        Ok(Self { 
-            websocket, 
-            handler: CallbackHandler::new()
+    /// Calls like `call`, giving up once `timeout` has passed without a RESULT. The call is
+    /// then canceled with `mode` and this fails with `Error::Timeout`.
+    pub async fn call_with_timeout(&self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: CallOptions, timeout: Duration, mode: CancelMode) -> Result<MessageResult, Error> {
+        let pending = self.request(|handler| handler.call(procedure, args, kwargs, options))?;
+        let request = pending.request();
+        match tokio::time::timeout(timeout, pending).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.request(|handler| {
+                    handler.abandon(request);
+                    handler.cancel(request, mode)
+                })?;
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Asks the router to cancel the call `request`, which then fails with the router's
+    /// `wamp.error.canceled` ERROR. With `CancelMode::Kill` the callee is interrupted first.
+    pub fn cancel(&self, request: u64, mode: CancelMode) -> Result<(), Error> {
+        self.request(|handler| handler.cancel(request, mode))
+    }
+
+    /// Calls with `receive_progress` set. The stream yields each progressive RESULT and
+    /// ends after the final RESULT, or after yielding the ERROR the call failed with.
+    pub fn call_progressive(&self, procedure: &str, args: JsonValue, kwargs: JsonValue, options: CallOptions) -> Result<ProgressiveResults, Error> {
+        self.request(|handler| handler.call_progressive(procedure, args, kwargs, options))
+    }
+
+    /// Publishes an event. With `acknowledge` set in `options`, this waits for the router
+    /// and returns the publication id; otherwise it returns `None` once the PUBLISH is queued.
+    pub async fn publish(&self, topic: &str, args: JsonValue, kwargs: JsonValue, options: PublishOptions) -> Result<Option<u64>, Error> {
+        match self.request(|handler| handler.publish(topic, args, kwargs, options))? {
+            Some(pending) => Ok(Some(pending.await?.publication)),
+            None => Ok(None)
+        }
+    }
+
+    /// Removes the handler of `subscription`, unsubscribing from the router once no other
+    /// handler shares the subscription.
+    pub async fn unsubscribe(&self, subscription: &Subscription) -> Result<Unsubscribed, Error> {
+        self.request(|handler| handler.unsubscribe(subscription))?.await
+    }
+
+    /// Subscribes like `subscribe`, decoding every event into `T` as described in
+    /// `typed::from_payload`. Events that do not decode reach `on_event` as `Error::Payload`.
+    pub async fn subscribe_typed<T, F>(&self, topic: &str, options: SubscribeOptions, on_event: F) -> Result<Subscription, Error>
+    where
+        T: DeserializeOwned,
+        F: Fn(Context, Result<T, Error>) -> Context + Send + Sync + 'static
+    {
+        self.subscribe(topic, options, Box::new(move |context, received| on_event(context, received.decode()))).await
+    }
+
+    /// Subscribes `on_event` like `subscribe`, first delivering the events `history` selects
+    /// from the router's event history, oldest first. Live events received while the
+    /// history is fetched wait for it, and events found in both are delivered only once.
+    /// After a reconnect the history is fetched again, so events published while the
+    /// session was down are not lost. The router has to keep an event history for `topic`,
+    /// otherwise the subscription is dropped again and the router's error returned.
+    pub async fn subscribe_with_history(&self, topic: &str, options: SubscribeOptions, history: History, on_event: Callback<ReceivedEvent>) -> Result<Subscription, Error> {
+        let on_event: Arc<Callback<ReceivedEvent>> = Arc::new(on_event);
+        let replay = Arc::new(Mutex::new(Replay::new(history)));
+        let subscription = self.subscribe(topic, options, Box::new({
+            let (on_event, replay) = (on_event.clone(), replay.clone());
+            move |context, received| match Replay::lock(&replay).admit(received) {
+                Some(received) => on_event(context, received),
+                None => context
+            }
+        })).await?;
+
+        if let Err(error) = self.replay_history(&subscription, &replay, &on_event).await {
+            let _ = self.unsubscribe(&subscription).await;
+            return Err(error);
+        }
+
+        let client = self.downgrade();
+        let restored = subscription.clone();
+        let on_rejoined: Callback<Lifecycle> = Box::new(move |context, lifecycle| {
+            if let (Lifecycle::Rejoined(_), Some(client)) = (lifecycle, client.upgrade()) {
+                // Runs before the restored SUBSCRIBE is answered, so no live event slips past.
+                Replay::lock(&replay).hold();
+                let (subscription, replay, on_event) = (restored.clone(), replay.clone(), on_event.clone());
+                tokio::spawn(async move { client.replay_after_rejoin(subscription, replay, on_event).await });
+            }
+            context
+        });
+        // Kept with the handler, so it goes away once the subscription is unsubscribed.
+        lock(&self.handler).handler_lifecycles.insert(subscription.handler, on_rejoined);
+        Ok(subscription)
+    }
+
+    /// Fetches the history again for a subscription restored after a reconnect. When that
+    /// fails, the held back live events are delivered anyway and the error is reported
+    /// through `Events::Failure`.
+    async fn replay_after_rejoin(&self, subscription: Subscription, replay: Arc<Mutex<Replay>>, on_event: Arc<Callback<ReceivedEvent>>) {
+        let resubscribed = self.request(|handler| {
+            let resubscribed = handler.resubscribed(&subscription);
+            if resubscribed.is_none() {
+                handler.handler_lifecycles.remove(&subscription.handler);
+            }
+            Ok(resubscribed)
+        });
+        let Ok(Some(resubscribed)) = resubscribed else {
+            return;
+        };
+        let Ok(subscribed) = resubscribed.await else {
+            return;
+        };
+        let subscription = Subscription { subscription: subscribed.subscription, ..subscription };
+        if let Err(error) = self.replay_history(&subscription, &replay, &on_event).await {
+            let _ = self.deliver_history(&replay, &on_event, vec![]);
+            lock(&self.handler).report(error);
+        }
+    }
+
+    /// Fetches the history of `subscription` and delivers it, followed by the live events
+    /// held back meanwhile.
+    async fn replay_history(&self, subscription: &Subscription, replay: &Mutex<Replay>, on_event: &Callback<ReceivedEvent>) -> Result<(), Error> {
+        let history = Replay::lock(replay).history.clone();
+        let events = self.fetch_history(subscription, &history).await?;
+        self.deliver_history(replay, on_event, events)
+    }
+
+    /// Delivers `events` under the handler lock, so no live event can overtake them.
+    fn deliver_history(&self, replay: &Mutex<Replay>, on_event: &Callback<ReceivedEvent>, events: Vec<ReceivedEvent>) -> Result<(), Error> {
+        self.request(|handler| {
+            let events = Replay::lock(replay).release(events);
+            let mut context = handler.context();
+            for received in events {
+                context = on_event(context, received);
+            }
+            handler.merge(context.messages);
+            Ok(())
         })
     }
 
-    pub async fn handle_event(&mut self, event: WampEvents, context: Context) -> Context {
-        match event {
-            WampEvents::Welcome(welcome) => {
-                for callback in &self.handler.callbacks {
-                    if let Events::Welcome(cb) = callback {
-                        return cb(context, welcome.clone());
-                    }
-                };
-            },
-            WampEvents::Abort(abort) => {
-                for callback in &self.handler.callbacks {
-                    if let Events::Abort(cb) = callback {
-                        return cb(context, abort.clone());
-                    }
-                };
-            },
-            WampEvents::Challenge(challenge) => {
-                for callback in &self.handler.callbacks {
-                    if let Events::Challenge(cb) = callback {
-                        return cb(context, challenge);
-                    }
-                }
-            },
-            WampEvents::Goodbye(goodbye) => {
-                for callback in &self.handler.callbacks {
-                    if let Events::Goodbye(cb) = callback {
-                        return cb(context, goodbye);
-                    }
-                }
-            },
-            WampEvents::ErrorMessage(error) => {
+    async fn fetch_history(&self, subscription: &Subscription, history: &History) -> Result<Vec<ReceivedEvent>, Error> {
+        let args = json::array![subscription.subscription, history.limit()];
+        let result = self.call("wamp.subscription.get_events", args, JsonValue::Null, CallOptions::default()).await?;
 
-                match error.request_type {
+        let mut entries: Vec<&JsonValue> = result.args[0].members().filter(|entry| history.includes(entry)).collect();
+        entries.sort_by(|a, b| a["timestamp"].as_str().cmp(&b["timestamp"].as_str()));
+        entries.into_iter().map(|entry| ReceivedEvent::from_history(subscription, entry)).collect()
+    }
 
-                    Subscribe::ID => {
-                        for callback in &self.handler.on_subscribed {
-                            if callback.0 == error.request {    
-                                return callback.1(context , Err(error.clone()));
-                            }
-                        }
-                    }
+    /// Calls `procedure` with `arguments` encoded as described in `typed::to_payload`, and
+    /// decodes the result into `R`.
+    pub async fn call_typed<A: Serialize + ?Sized, R: DeserializeOwned>(&self, procedure: &str, arguments: &A) -> Result<R, Error> {
+        let (args, kwargs) = to_payload(arguments)?;
+        let result = self.call(procedure, args, kwargs, CallOptions::default()).await?;
+        from_payload(&result.args, &result.kwargs)
+    }
 
-                    Call::ID => {
-                        for callback in &self.handler.call_results {
-                            if callback.0 == error.request {
-                                return callback.1(context, Err(error));
-                            }
-                        }
-                    }
+    /// Publishes `payload` to `topic`, encoded as described in `typed::to_payload`. Returns
+    /// the publication id when `options` asks for an acknowledgement.
+    pub async fn publish_typed<T: Serialize + ?Sized>(&self, topic: &str, payload: &T, options: PublishOptions) -> Result<Option<u64>, Error> {
+        let (args, kwargs) = to_payload(payload)?;
+        self.publish(topic, args, kwargs, options).await
+    }
 
-                    Publish::ID => {
-                        for callback in &self.handler.call_results {
-                            if callback.0 == error.request {
-                                return callback.1(context, Err(error));
-                            }
-                        }
-                    }
+    /// Registers `procedure` for `uri`. Every invocation carries the called URI in
+    /// `details.procedure`, which for prefix and wildcard registrations is the concrete
+    /// procedure the caller asked for. `InvocationContext` reads it along with the caller's
+    /// identity.
+    pub async fn register(&self, uri: &str, options: RegisterOptions, procedure: Procedure) -> Result<Registered, Error> {
+        self.request(|handler| handler.register(uri, options, procedure))?.await
+    }
 
-                    Unsubscribe::ID => {
-                        for callback in &self.handler.unsubscribe_callbacks {
-                            if callback.0 == error.request {
-                                return  callback.1(context, Err(error));
-                            }
-                        }
-                    }
+    /// Registers a procedure whose handler runs on its own task. The caller gets the
+    /// result once the returned future resolves.
+    pub async fn register_async<F, Fut>(&self, uri: &str, options: RegisterOptions, procedure: F) -> Result<Registered, Error>
+    where
+        F: Fn(Invocation) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = InvocationResult> + Send + 'static
+    {
+        self.register_procedure(ProcedureDefinition::new(uri, procedure), options).await
+    }
 
-                    Register::ID => {
-                        for callback in &self.handler.register_callbacks {
-                            if callback.0 == error.request {
-                                return callback.1(context, Err(error));
-                            }
-                        }
-                    }
+    /// Registers a procedure whose handler returns a stream of results. When the caller
+    /// asked for progress, every result is sent as it comes and the last one as the final
+    /// result; otherwise only the last one is sent. An error ends the invocation.
+    pub async fn register_streaming<F, S>(&self, uri: &str, options: RegisterOptions, procedure: F) -> Result<Registered, Error>
+    where
+        F: Fn(Invocation) -> S + Send + Sync + 'static,
+        S: Stream<Item = InvocationResult> + Send + 'static
+    {
+        self.request(|handler| handler.register_streaming(uri, options, Box::new(move |invocation| procedure(invocation).boxed())))?.await
+    }
 
-                    Unregister::ID => {
-                        for callback in &self.handler.unregistered_callbacks {
-                            if callback.0 == error.request {
-                                return callback.1(context, Err(error));
-                            }
-                        }
-                    }
+    /// Registers a procedure generated by `#[procedure]`.
+    pub async fn register_procedure(&self, definition: ProcedureDefinition, options: RegisterOptions) -> Result<Registered, Error> {
+        self.request(|handler| handler.register_async(&definition.uri, options, definition.procedure))?.await
+    }
 
-                    _ => {
-                        for callback in &self.handler.callbacks {
-                            if let Events::Error(cb) = callback {
-                                return cb(context, error.clone());
-                            }
-                        }
-                    }
-                }
-            },
-            WampEvents::Published(published) => {
-                for callback in &self.handler.publish_callbacks {
-                    if published.request == callback.0 {
-                        return callback.1(context, Ok(published));
-                    }
-                }
-            },
-            WampEvents::Subscribed(subscribed) => {
-                for callback in &mut self.handler.subscriptions {
-                    callback.2 = Some(subscribed.subscription);
-                }
-
-                for callback in &self.handler.on_subscribed {
-                    if subscribed.request == callback.0 {
-                        return callback.1(context, Ok(subscribed.clone()));
-                    }
-                };
-            },
-            WampEvents::Unsubscribed(unsubscribed) => {
-                for callback in &self.handler.unsubscribe_callbacks {
-                    if unsubscribed.request == callback.0 {
-                        return callback.1(context, Ok(unsubscribed));
-                    }
-                }
-            },
-            WampEvents::Event(event) => {
-                for callback in &self.handler.subscriptions {
-                    if let Some(subscription) = callback.2 {
-                        if event.subscription == subscription {
-                            return callback.1(context, event);
-                        }
-                    }
-                }
-            },
-            WampEvents::MessageResult(result) => {
-                for callback in &self.handler.call_results {
-                    if callback.0 == result.request {
-                        return callback.1(context, Ok(result));
-                    }
-                }
-            },
-            WampEvents::Registered(registered) => {
-                for callback in &self.handler.register_callbacks {
-                    if callback.0 == registered.request {
-                        return callback.1(context, Ok(registered));
-                    }
-                }
-            },
-            WampEvents::Unregistered(unregistered) => {
-                for callback in &self.handler.unregistered_callbacks {
-                    if callback.0 == unregistered.request {
-                        return callback.1(context, Ok(unregistered));
-                    }
-                }
-            },
-            WampEvents::Interrupt(interrupt) => {
-                for callback in &self.handler.callbacks {
-                    if let Events::Interrupt(cb) = callback {
-                        return cb(context, interrupt);
-                    }
-                }
-            },
-            WampEvents::Yield(yield_frame) => {
-                for callback in &self.handler.callbacks {
-                    if let Events::Yield(cb) = callback {
-                        return cb(context, yield_frame);
-                    }    
-                }
+    /// Registers every procedure of `service` with the `invoke` policy, for instance to
+    /// share the calls among replicas of the service. Each procedure matches its URI exactly.
+    pub async fn mount<S: Service>(&self, service: S, invoke: InvokePolicy) -> Result<Vec<Registered>, Error> {
+        let options = RegisterOptions::default().invoke(invoke);
+        let mut registered = vec![];
+        for definition in Arc::new(service).procedures() {
+            registered.push(self.register_procedure(definition, options).await?);
+        }
+        Ok(registered)
+    }
+
+    /// Unregisters the procedure `registered` was returned for. It is not registered again
+    /// after a reconnect, even when the reconnect is still restoring it.
+    pub async fn unregister(&self, registered: &Registered) -> Result<Unregistered, Error> {
+        self.request(|handler| handler.unregister(registered))?.await
+    }
+
+    /// Connects to the router and spawns the session driver for the socket. The
+    /// subprotocol of `serializer` is added to `options`.
+    pub async fn connect<S: Serializer + 'static>(url: &str, options: &mut WebSocketBuilder, serializer: S) -> Result<Self, Error> {
+        let websocket = options.add_subprotocol(serializer.subprotocol()).connect(url).await?;
+        Ok(Self::start(Box::new(WebSocketTransport::new(websocket)), Arc::new(serializer), None))
+    }
+
+    /// Connects over RawSocket to a `tcp://host:port` or `unix:///path` endpoint.
+    pub async fn connect_rawsocket<S: Serializer + 'static>(url: &str, serializer: S) -> Result<Self, Error> {
+        let transport = RawSocketTransport::connect(url, serializer.rawsocket_id(), MAX_MESSAGE_LENGTH).await?;
+        Ok(Self::start(Box::new(transport), Arc::new(serializer), None))
+    }
+
+    /// Starts a session over an already connected transport.
+    pub fn with_transport<T: Transport + 'static, S: Serializer + 'static>(transport: T, serializer: S) -> Self {
+        Self::start(Box::new(transport), Arc::new(serializer), None)
+    }
+
+    /// Connects like `connect`, and reconnects following `policy` whenever the socket
+    /// fails. After reconnecting, the realm is joined again and every subscription and
+    /// registration still held is restored. Progress is reported through `Events::Lifecycle`.
+    /// Every connection is opened with a builder made by `options`, so that headers and TLS
+    /// settings apply to reconnects as well, for example `WebSocket::builder`.
+    pub async fn connect_reconnecting<S, F>(url: &str, options: F, serializer: S, policy: ReconnectPolicy) -> Result<Self, Error>
+    where
+        S: Serializer + 'static,
+        F: Fn() -> WebSocketBuilder + Send + Sync + 'static
+    {
+        let url = url.to_string();
+        let subprotocol = serializer.subprotocol();
+        let connector: Connector = Arc::new(move || {
+            let url = url.clone();
+            let mut builder = options();
+            Box::pin(async move {
+                builder.add_subprotocol(subprotocol);
+                let websocket = builder.connect(&url).await?;
+                Ok(Box::new(WebSocketTransport::new(websocket)) as Box<dyn Transport>)
+            })
+        });
+        let transport = connector().await?;
+        Ok(Self::start(transport, Arc::new(serializer), Some((connector, policy))))
+    }
+
+    fn start(transport: Box<dyn Transport>, serializer: Arc<dyn Serializer>, reconnect: Option<(Connector, ReconnectPolicy)>) -> Self {
+        let handler = Arc::new(Mutex::new(CallbackHandler::new()));
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+        let (closed_sender, closed) = watch::channel(false);
+        let driver = SessionDriver::new(transport, serializer, outgoing_receiver, outgoing.clone(), handler.clone(), closed_sender, reconnect);
+        tokio::spawn(driver.run());
+        
+        Self { 
+            handler,
+            outgoing,
+            closed
+        }
+    }
+
+    /// Waits until the session driver stops: after `leave` or `close`, after the router
+    /// sent GOODBYE, or once the connection is lost for good.
+    pub async fn loop_messages(&self) {
+        let mut closed = self.closed.clone();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Leaves the realm with GOODBYE `reason`, such as `wamp.close.close_realm`, and waits
+    /// up to `LEAVE_TIMEOUT` for the router's GOODBYE before closing the connection.
+    /// Requests still pending fail with `Error::SessionClosed`.
+    pub async fn leave(&self, reason: &str) -> Result<Goodbye, Error> {
+        self.leave_with_timeout(reason, LEAVE_TIMEOUT).await
+    }
+
+    /// Leaves like `leave`, waiting up to `timeout` for the router's GOODBYE. When none
+    /// arrives in time, the connection is closed anyway and this fails with `Error::Timeout`.
+    pub async fn leave_with_timeout(&self, reason: &str, timeout: Duration) -> Result<Goodbye, Error> {
+        let left = self.request(|handler| handler.leave(reason))?;
+        match tokio::time::timeout(timeout, left).await {
+            Ok(goodbye) => {
+                let goodbye = goodbye.map_err(|_| Error::SessionClosed)?;
+                self.loop_messages().await;
+                Ok(goodbye)
             },
-            _ => {
-                println!("Wamp Server frame received... Not handling.");
+            Err(_) => {
+                self.close().await;
+                Err(Error::Timeout)
             }
-        } 
-        context
-    }
-
-    pub async fn loop_messages(&mut self){
-        loop {
-            match self.websocket.receive().await {
-                Ok(f) => {
-                    if let Some(message) = f.as_text() {
-                        let message = message.0;
-                        let event = WampEvents::parse_message(message).unwrap();
-                        let original_context = Context { messages: CallbackHandler::new() };
-                        let context = self.handle_event(event.clone(), original_context).await;
-                        self.handler.merge(context.messages);
-                        let mut to_send = vec![];
-                        self.handler.message_queue.retain(|item| {
-                            to_send.push(item.clone());
-                            false
-                        });
-
-                        match event {
-                            WampEvents::Unregistered(unregistered) => {
-                                self.handler.unregistered_callbacks.retain(|i| {
-                                    if i.0 == unregistered.request {
-                                        return false
-                                    }
-                                    true
-                                });
-                            },
-                            WampEvents::Unsubscribed(unsubscribed ) => {
-                                self.handler.unsubscribe_callbacks.retain(|i| {
-                                    if i.0 == unsubscribed.request {
-                                        return false
-                                    }
-                                    true
-                                });
-                            },
-                            WampEvents::Subscribed(subscribed) => {
-                                self.handler.on_subscribed.retain(|i| {
-                                    if i.0 == subscribed.request {
-                                        return false
-                                    }
-                                    true
-                                })
-                            },
-                            WampEvents::MessageResult(result) => {
-                                self.handler.call_results.retain(|i| {
-                                    if i.0 == result.request {
-                                        return false
-                                    }
-                                    true
-                                })
-                            },
-                            WampEvents::Registered(registered) => {
-                                self.handler.register_callbacks.retain(|i| {
-                                    if i.0 == registered.request {
-                                        return false
-                                    }
-                                    true
-                                })
-                            },
-                            WampEvents::Published(published) => {
-                                self.handler.publish_callbacks.retain(|i| {
-                                    if i.0 == published.request {
-                                        return false
-                                    }
-                                    true
-                                })
-                            }
-                            _ => { }
-                        };
-
-                        for message in to_send {
-                            let _ = self.websocket.send_text(message.to_string()).await;
-                        }
-                    }
-                },
-                Err(_e) => {
-                },
-            };   
         }
     }
 
-    pub async fn send<M: WampMessageTrait>(&mut self, message: M) -> Result<(), Error> {
-        Ok(self.websocket.send_text(
+    /// Closes the connection without leaving the realm first. Requests still pending
+    /// fail with `Error::SessionClosed`.
+    pub async fn close(&self) {
+        {
+            let mut handler = lock(&self.handler);
+            handler.closing = true;
+            handler.shutdown.cancel();
+        }
+        self.loop_messages().await;
+    }
+
+    pub async fn send<M: WampMessageTrait>(&self, message: M) -> Result<(), Error> {
+        self.outgoing.send(
         message
                 .to_json()
-                .map_err(|e| Error::JsonError(e))?
-                .to_string()
+                .map_err(Error::JsonError)?
             )
-            .await
-            .map_err(|e| Error::WsError(e))?)
+            .map_err(|_| Error::SessionClosed)
+    }
+
+    /// Builds a request against the shared handler and hands its queued messages to the
+    /// driver while still holding the lock, so messages keep the order they were queued in.
+    fn request<T>(&self, build: impl FnOnce(&mut CallbackHandler) -> Result<T, Error>) -> Result<T, Error> {
+        let mut handler = lock(&self.handler);
+        let pending = build(&mut handler)?;
+        for message in std::mem::take(&mut handler.message_queue) {
+            self.outgoing.send(message).map_err(|_| Error::SessionClosed)?;
+        }
+        Ok(pending)
+    }
+}
+
+/// Handle that does not keep the session alive, for callbacks stored in the handler.
+struct WeakClient {
+    handler: Weak<Mutex<CallbackHandler>>,
+    outgoing: mpsc::UnboundedSender<JsonValue>,
+    closed: watch::Receiver<bool>
+}
+
+impl WeakClient {
+    fn upgrade(&self) -> Option<WampClient> {
+        Some(WampClient { handler: self.handler.upgrade()?, outgoing: self.outgoing.clone(), closed: self.closed.clone() })
+    }
+}
+
+impl WampClient {
+    fn downgrade(&self) -> WeakClient {
+        WeakClient { handler: Arc::downgrade(&self.handler), outgoing: self.outgoing.clone(), closed: self.closed.clone() }
+    }
+}
+
+/// Delivery state of a `subscribe_with_history` subscription.
+struct Replay {
+    history: History,
+    /// Live events held back while the history is fetched.
+    waiting: Option<Vec<ReceivedEvent>>,
+    /// The last publications delivered, as many as a fetch returns, so that events fetched
+    /// again are skipped.
+    recent: VecDeque<u64>
+}
+
+impl Replay {
+    fn new(history: History) -> Replay {
+        Replay { history, waiting: Some(vec![]), recent: VecDeque::new() }
+    }
+
+    fn lock(replay: &Mutex<Replay>) -> MutexGuard<'_, Replay> {
+        replay.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Holds back live events until the next `release`.
+    fn hold(&mut self) {
+        self.waiting.get_or_insert_with(Vec::new);
+    }
+
+    fn admit(&mut self, received: ReceivedEvent) -> Option<ReceivedEvent> {
+        if let Some(waiting) = &mut self.waiting {
+            waiting.push(received);
+            return None;
+        }
+        self.remember(received.event.publication).then_some(received)
+    }
+
+    /// Stops holding back live events. Returns `history` followed by the held back events,
+    /// leaving out those delivered before.
+    fn release(&mut self, history: Vec<ReceivedEvent>) -> Vec<ReceivedEvent> {
+        let waiting = self.waiting.take().unwrap_or_default();
+        history.into_iter().chain(waiting).filter(|received| self.remember(received.event.publication)).collect()
+    }
+
+    /// Records `publication` as delivered. Returns false when it already was.
+    fn remember(&mut self, publication: u64) -> bool {
+        if self.recent.contains(&publication) {
+            return false;
+        }
+        if self.recent.len() as u64 >= self.history.limit().max(1) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(publication);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wamp_helpers::messages::{Call, Hello, Subscribe};
+
+    use super::*;
+    use crate::{auth::{Anonymous, Ticket, WampCra}, callback::{Payload, ProcedureError}, options::Match, ppt::{PayloadKey, PrefixKeyRing}, serializer::Json, transport::{loopback, LoopbackTransport}};
+
+    async fn joined() -> (WampClient, LoopbackTransport) {
+        let (transport, mut router) = loopback();
+        let client = WampClient::with_transport(transport, Json);
+        let join = tokio::spawn({
+            let client = client.clone();
+            async move { client.join("realm1", Roles::default(), Anonymous).await }
+        });
+
+        let hello = router.expect().await;
+        assert_eq!((hello[0].as_u64(), hello[1].as_str()), (Some(Hello::ID), Some("realm1")));
+        router.reply(r#"[2, 9, {"roles": {"broker": {}, "dealer": {}}}]"#).await;
+        assert_eq!(join.await.unwrap().unwrap().session, 9);
+        (client, router)
+    }
+
+    /// Starts joining with `authenticators`, returning the join and the router after HELLO.
+    async fn joining(authenticators: Vec<Arc<dyn Authenticator>>) -> (tokio::task::JoinHandle<Result<SessionDetails, Error>>, LoopbackTransport) {
+        let (transport, mut router) = loopback();
+        let client = WampClient::with_transport(transport, Json);
+        let join = tokio::spawn(async move { client.join_with_methods("realm1", Roles::default(), authenticators).await });
+        let hello = router.expect().await;
+        assert_eq!(hello[0].as_u64(), Some(Hello::ID));
+        (join, router)
+    }
+
+    #[tokio::test]
+    async fn answers_a_challenge_with_the_challenged_method() {
+        let (join, mut router) = joining(vec![Arc::new(WampCra::new("joe", "secret")), Arc::new(Ticket::with_authid("joe", "t0ken"))]).await;
+        router.reply(r#"[4, "ticket", {}]"#).await;
+        assert_eq!(router.expect().await, json::array![5, "t0ken", {}]);
+        router.reply(r#"[2, 9, {"authid": "joe", "authmethod": "ticket"}]"#).await;
+        assert_eq!(join.await.unwrap().unwrap().session, 9);
+    }
+
+    #[tokio::test]
+    async fn aborted_joins_fail_with_the_reason() {
+        let (join, mut router) = joining(vec![Arc::new(Anonymous)]).await;
+        router.reply(r#"[3, {"message": "no such realm"}, "wamp.error.no_such_realm"]"#).await;
+        match join.await.unwrap() {
+            Err(Error::Abort { reason, details }) => assert_eq!((reason.as_str(), &details), ("wamp.error.no_such_realm", &json::object! { message: "no such realm" })),
+            other => panic!("unexpected reply {other:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn aborts_challenges_no_authenticator_answers() {
+        let (join, mut router) = joining(vec![Arc::new(Ticket::new("t0ken"))]).await;
+        router.reply(r#"[4, "wampcra", {"challenge": "[1, 2, 3]"}]"#).await;
+        let abort = router.expect().await;
+        assert_eq!((abort[0].as_u64(), abort[2].as_str()), (Some(3), Some("wamp.error.cannot_authenticate")));
+        assert!(matches!(join.await.unwrap(), Err(Error::Authentication(_))));
+    }
+
+    #[tokio::test]
+    async fn delivers_events_from_a_scripted_router() {
+        let (client, mut router) = joined().await;
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let subscription = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.subscribe("com.example", SubscribeOptions::default(), Box::new(move |context, received| {
+                    let _ = events_sender.send(received.event.args.clone());
+                    context
+                })).await
+            }
+        });
+
+        let subscribe = router.expect().await;
+        assert_eq!((subscribe[0].as_u64(), subscribe[3].as_str()), (Some(Subscribe::ID), Some("com.example")));
+        router.reply("[33, 1, 5]").await;
+        assert_eq!(subscription.await.unwrap().unwrap().subscription, 5);
+
+        router.reply(r#"[36, 5, 1, {}, ["hello"]]"#).await;
+        assert_eq!(events.recv().await.unwrap(), json::array!["hello"]);
+    }
+
+    #[tokio::test]
+    async fn handlers_unsubscribe_through_their_context() {
+        let (client, mut router) = joined().await;
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let (unsubscribed_sender, mut unsubscribed) = mpsc::unbounded_channel();
+        let own: Arc<Mutex<Option<Subscription>>> = Arc::default();
+        let subscription = tokio::spawn({
+            let (client, own) = (client.clone(), own.clone());
+            async move {
+                client.subscribe("com.example", SubscribeOptions::default(), Box::new(move |mut context, received| {
+                    let _ = events_sender.send(received.event.publication);
+                    if let Some(subscription) = own.lock().unwrap().take() {
+                        let _ = unsubscribed_sender.send(context.unsubscribe(&subscription).unwrap());
+                    }
+                    context
+                })).await
+            }
+        });
+        router.expect().await;
+        router.reply("[33, 1, 5]").await;
+        *own.lock().unwrap() = Some(subscription.await.unwrap().unwrap());
+
+        router.reply("[36, 5, 1, {}]").await;
+        assert_eq!(events.recv().await, Some(1));
+        let unsubscribe = router.expect().await;
+        assert_eq!((unsubscribe[0].as_u64(), &unsubscribe[2]), (Some(34), &JsonValue::from(5)));
+        router.reply(&json::array![35, unsubscribe[1].clone()].dump()).await;
+        unsubscribed.recv().await.unwrap().await.unwrap();
+
+        router.reply("[36, 5, 2, {}]").await;
+        router.reply(r#"[6, {}, "wamp.close.system_shutdown"]"#).await;
+        router.expect().await;
+        client.loop_messages().await;
+        assert_eq!(events.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_subscriptions_answered_out_of_order_keep_their_handlers() {
+        let (client, mut router) = joined().await;
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let subscribe = |topic: &'static str| tokio::spawn({
+            let (client, events_sender) = (client.clone(), events_sender.clone());
+            async move {
+                client.subscribe(topic, SubscribeOptions::default(), Box::new(move |context, received| {
+                    let _ = events_sender.send((topic, received.event.args[0].clone()));
+                    context
+                })).await
+            }
+        });
+        let first = subscribe("com.example.first");
+        let first_request = router.expect().await;
+        let second = subscribe("com.example.second");
+        let second_request = router.expect().await;
+
+        router.reply(&json::array![33, second_request[1].clone(), 200].dump()).await;
+        router.reply(&json::array![33, first_request[1].clone(), 100].dump()).await;
+        assert_eq!(first.await.unwrap().unwrap().subscription, 100);
+        assert_eq!(second.await.unwrap().unwrap().subscription, 200);
+
+        router.reply(r#"[36, 200, 1, {}, ["b"]]"#).await;
+        router.reply(r#"[36, 100, 2, {}, ["a"]]"#).await;
+        assert_eq!(events.recv().await.unwrap(), ("com.example.second", "b".into()));
+        assert_eq!(events.recv().await.unwrap(), ("com.example.first", "a".into()));
+    }
+
+    #[tokio::test]
+    async fn delivers_event_history_before_live_events_and_only_once() {
+        let (client, mut router) = joined().await;
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let subscription = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.subscribe_with_history("com.example", SubscribeOptions::default(), History::Last(2), Box::new(move |context, received| {
+                    let _ = events_sender.send(received.event.args[0].clone());
+                    context
+                })).await
+            }
+        });
+
+        router.expect().await;
+        router.reply("[33, 1, 5]").await;
+        router.reply(r#"[36, 5, 3, {}, ["c"]]"#).await;
+        assert_eq!(router.expect().await, json::array![48, 2, {}, "wamp.subscription.get_events", [5, 2]]);
+        router.reply(r#"[50, 2, {}, [[
+            {"timestamp": "2026-01-01T00:00:02Z", "publication": 3, "args": ["c"]},
+            {"timestamp": "2026-01-01T00:00:01Z", "publication": 2, "args": ["b"]}
+        ]]]"#).await;
+        assert_eq!(subscription.await.unwrap().unwrap().subscription, 5);
+
+        router.reply(r#"[36, 5, 4, {}, ["d"]]"#).await;
+        for expected in ["b", "c", "d"] {
+            assert_eq!(events.recv().await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_drops_the_event_history_refetch() {
+        let (client, mut router) = joined().await;
+        let subscription = tokio::spawn({
+            let client = client.clone();
+            async move { client.subscribe_with_history("com.example", SubscribeOptions::default(), History::Last(2), Box::new(|context, _| context)).await }
+        });
+        router.answer("[33, 1, 5]").await;
+        router.answer("[50, 2, {}, [[]]]").await;
+        let subscription = subscription.await.unwrap().unwrap();
+        assert_eq!(lock(&client.handler).handler_lifecycles.len(), 1);
+
+        let unsubscribed = tokio::spawn({
+            let client = client.clone();
+            async move { client.unsubscribe(&subscription).await }
+        });
+        router.answer("[35, 3]").await;
+        unsubscribed.await.unwrap().unwrap();
+        assert!(lock(&client.handler).handler_lifecycles.is_empty());
+    }
+
+    /// A joined client that reconnects to `rerouter` once `router` goes away, and reports
+    /// the session id of every rejoin.
+    async fn reconnecting() -> (WampClient, LoopbackTransport, LoopbackTransport, mpsc::UnboundedReceiver<u64>) {
+        let (transport, mut router) = loopback();
+        let (reconnected, rerouter) = loopback();
+        let reconnected = Arc::new(Mutex::new(Some(reconnected)));
+        let connector: Connector = Arc::new(move || {
+            let transport = reconnected.lock().unwrap().take();
+            Box::pin(async move { transport.map(|t| Box::new(t) as Box<dyn Transport>).ok_or(Error::SessionClosed) })
+        });
+        let policy = ReconnectPolicy { initial_delay: Duration::from_millis(1), jitter: 0.0, ..ReconnectPolicy::default() };
+        let client = WampClient::start(Box::new(transport), Arc::new(Json), Some((connector, policy)));
+        let (lifecycle, rejoined) = mpsc::unbounded_channel();
+        client.on(Events::Lifecycle(Box::new(move |context, event| {
+            if let Lifecycle::Rejoined(details) = event {
+                let _ = lifecycle.send(details.session);
+            }
+            context
+        })));
+
+        let join = tokio::spawn({
+            let client = client.clone();
+            async move { client.join("realm1", Roles::default(), Anonymous).await }
+        });
+        router.expect().await;
+        router.reply("[2, 9, {}]").await;
+        join.await.unwrap().unwrap();
+        (client, router, rerouter, rejoined)
+    }
+
+    #[tokio::test]
+    async fn restores_the_session_after_reconnecting() {
+        let (client, mut router, mut rerouter, mut rejoined) = reconnecting().await;
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let subscribed = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.subscribe("com.example", SubscribeOptions::default(), Box::new(move |context, received| {
+                    let _ = events_sender.send(received.event.publication);
+                    context
+                })).await
+            }
+        });
+        router.expect().await;
+        router.reply("[33, 1, 5]").await;
+        subscribed.await.unwrap().unwrap();
+
+        // Sent to the first router, which goes away before answering.
+        let registered = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.register_async("com.example.add", RegisterOptions::default(), |_| async { Ok(Payload::new(JsonValue::Null, JsonValue::Null)) }).await
+            }
+        });
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, u32>("com.example.add", &(1, 2)).await }
+        });
+        router.expect().await;
+        router.expect().await;
+        drop(router);
+
+        assert_eq!(rerouter.expect().await[0].as_u64(), Some(Hello::ID));
+        assert!(matches!(call.await.unwrap(), Err(Error::SessionClosed)));
+        rerouter.reply("[2, 10, {}]").await;
+        assert_eq!(rejoined.recv().await, Some(10));
+        assert_eq!(rerouter.expect().await, json::array![32, 1, {}, "com.example"]);
+        assert_eq!(rerouter.expect().await, json::array![64, 2, {}, "com.example.add"]);
+
+        rerouter.reply("[65, 2, 200]").await;
+        assert_eq!(registered.await.unwrap().unwrap().registration, 200);
+        rerouter.reply("[33, 1, 6]").await;
+        rerouter.reply("[36, 6, 42, {}]").await;
+        assert_eq!(events.recv().await, Some(42));
+    }
+
+    #[tokio::test]
+    async fn does_not_reconnect_a_session_that_is_leaving() {
+        let (client, mut router, mut rerouter, _) = reconnecting().await;
+        let left = tokio::spawn({
+            let client = client.clone();
+            async move { client.leave("wamp.close.close_realm").await }
+        });
+        router.expect().await;
+        drop(router);
+
+        assert!(matches!(left.await.unwrap(), Err(Error::SessionClosed)));
+        client.loop_messages().await;
+        assert!(rerouter.receive().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unregistered_procedures_are_not_restored() {
+        let (client, mut router, mut rerouter, mut rejoined) = reconnecting().await;
+        let register = |uri: &'static str| tokio::spawn({
+            let client = client.clone();
+            async move { client.register_async(uri, RegisterOptions::default(), |_| async { Ok(Payload::new(JsonValue::Null, JsonValue::Null)) }).await }
+        });
+        let (first, second) = (register("com.example.a"), register("com.example.b"));
+        router.answer("[65, 1, 100]").await;
+        router.answer("[65, 2, 101]").await;
+        let (first, second) = (first.await.unwrap().unwrap(), second.await.unwrap().unwrap());
+
+        let unregistered = tokio::spawn({
+            let client = client.clone();
+            async move { client.unregister(&first).await }
+        });
+        assert_eq!(router.answer("[67, 3]").await, json::array![66, 3, 100]);
+        unregistered.await.unwrap().unwrap();
+        drop(router);
+
+        rerouter.expect().await;
+        rerouter.reply("[2, 10, {}]").await;
+        assert_eq!(rejoined.recv().await, Some(10));
+        assert_eq!(rerouter.expect().await, json::array![64, 2, {}, "com.example.b"]);
+
+        // Unregistered while being restored: the UNREGISTER follows the new REGISTERED.
+        let unregistered = tokio::spawn({
+            let client = client.clone();
+            async move { client.unregister(&second).await }
+        });
+        tokio::task::yield_now().await;
+        rerouter.reply("[65, 2, 201]").await;
+        assert_eq!(rerouter.answer("[67, 4]").await, json::array![66, 4, 201]);
+        unregistered.await.unwrap().unwrap();
+        assert!(lock(&client.handler).registrations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetches_event_history_again_after_reconnecting() {
+        let (client, mut router, mut rerouter, mut rejoined) = reconnecting().await;
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let subscription = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.subscribe_with_history("com.example", SubscribeOptions::default(), History::Last(2), Box::new(move |context, received| {
+                    let _ = events_sender.send(received.event.publication);
+                    context
+                })).await
+            }
+        });
+        router.expect().await;
+        router.reply("[33, 1, 5]").await;
+        router.expect().await;
+        router.reply(r#"[50, 2, {}, [[{"timestamp": "2026-01-01T00:00:01Z", "publication": 1}]]]"#).await;
+        subscription.await.unwrap().unwrap();
+        router.reply("[36, 5, 2, {}]").await;
+        assert_eq!((events.recv().await, events.recv().await), (Some(1), Some(2)));
+        drop(router);
+
+        rerouter.expect().await;
+        rerouter.reply("[2, 10, {}]").await;
+        assert_eq!(rejoined.recv().await, Some(10));
+        assert_eq!(rerouter.expect().await, json::array![32, 1, {}, "com.example"]);
+        rerouter.reply("[33, 1, 6]").await;
+        rerouter.reply("[36, 6, 4, {}]").await;
+        assert_eq!(rerouter.expect().await, json::array![48, 3, {}, "wamp.subscription.get_events", [6, 2]]);
+        rerouter.reply(r#"[50, 3, {}, [[
+            {"timestamp": "2026-01-01T00:00:03Z", "publication": 3},
+            {"timestamp": "2026-01-01T00:00:02Z", "publication": 2}
+        ]]]"#).await;
+        assert_eq!((events.recv().await, events.recv().await), (Some(3), Some(4)));
+
+        rerouter.reply("[36, 6, 5, {}]").await;
+        assert_eq!(events.recv().await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn filters_event_history_by_timestamp() {
+        let (client, mut router) = joined().await;
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let history = History::Since { timestamp: "2026-01-01T00:00:01Z".to_string(), limit: 10 };
+        let subscription = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.subscribe_with_history("com.example", SubscribeOptions::default(), history, Box::new(move |context, received| {
+                    let _ = events_sender.send(received.event.publication);
+                    context
+                })).await
+            }
+        });
+
+        router.expect().await;
+        router.reply("[33, 1, 5]").await;
+        assert_eq!(router.expect().await, json::array![48, 2, {}, "wamp.subscription.get_events", [5, 10]]);
+        router.reply(r#"[50, 2, {}, [[
+            {"timestamp": "2026-01-01T00:00:02Z", "publication": 3},
+            {"timestamp": "2026-01-01T00:00:01Z", "publication": 2},
+            {"timestamp": "2026-01-01T00:00:00Z", "publication": 1}
+        ]]]"#).await;
+        subscription.await.unwrap().unwrap();
+
+        router.reply("[36, 5, 4, {}]").await;
+        assert_eq!((events.recv().await, events.recv().await), (Some(3), Some(4)));
+    }
+
+    #[tokio::test]
+    async fn fails_calls_with_the_router_error_and_stops_with_the_transport() {
+        let (client, mut router) = joined().await;
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.call("com.example.add", json::array![1, 2], JsonValue::Null, CallOptions::default()).await
+            }
+        });
+
+        assert_eq!(router.expect().await[0].as_u64(), Some(Call::ID));
+        router.reply(r#"[8, 48, 1, {}, "wamp.error.no_such_procedure"]"#).await;
+        match call.await.unwrap() {
+            Err(Error::Wamp(error)) => assert_eq!(error.uri, "wamp.error.no_such_procedure"),
+            other => panic!("unexpected reply {other:?}")
+        }
+
+        drop(router);
+        client.loop_messages().await;
+    }
+
+    #[tokio::test]
+    async fn encodes_and_decodes_typed_calls() {
+        let (client, mut router) = joined().await;
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, u32>("com.example.add", &(1, 2)).await }
+        });
+
+        let sent = router.expect().await;
+        assert_eq!((sent[0].as_u64(), &sent[4]), (Some(Call::ID), &json::array![1, 2]));
+        router.reply("[50, 1, {}, [3]]").await;
+        assert_eq!(call.await.unwrap().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn streams_progressive_results_until_the_final_one() {
+        let (client, mut router) = joined().await;
+        let mut results = client.call_progressive("com.example.tail", JsonValue::Null, JsonValue::Null, CallOptions::default()).unwrap();
+
+        assert_eq!(router.expect().await[2], json::object! { receive_progress: true });
+        router.reply(r#"[50, 1, {"progress": true}, ["a"]]"#).await;
+        router.reply(r#"[50, 1, {"progress": true}, ["b"]]"#).await;
+        router.reply(r#"[50, 1, {}, ["c"]]"#).await;
+
+        let mut chunks = vec![];
+        while let Some(result) = results.next().await {
+            chunks.push(result.unwrap().args[0].to_string());
+        }
+        assert_eq!(chunks, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn yields_progress_only_when_the_caller_asked_for_it() {
+        let (client, mut router) = joined().await;
+        let registered = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.register_streaming("com.example.count", RegisterOptions::default(), |_| {
+                    futures::stream::iter((1..=3).map(|i| Ok(Payload::new(json::array![i], JsonValue::Null))))
+                }).await
+            }
+        });
+        router.expect().await;
+        router.reply("[65, 1, 100]").await;
+        registered.await.unwrap().unwrap();
+
+        router.reply(r#"[68, 7, 100, {"receive_progress": true}]"#).await;
+        assert_eq!(router.expect().await, json::array![70, 7, { progress: true }, [1]]);
+        assert_eq!(router.expect().await, json::array![70, 7, { progress: true }, [2]]);
+        assert_eq!(router.expect().await, json::array![70, 7, {}, [3]]);
+
+        router.reply("[68, 8, 100, {}]").await;
+        assert_eq!(router.expect().await, json::array![70, 8, {}, [3]]);
+    }
+
+    #[tokio::test]
+    async fn cancels_calls_that_time_out() {
+        let (client, mut router) = joined().await;
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move {
+                let options = CallOptions::default().disclose_me();
+                client.call_with_timeout("com.example.slow", JsonValue::Null, JsonValue::Null, options, Duration::from_millis(10), CancelMode::KillNoWait).await
+            }
+        });
+
+        assert_eq!(router.expect().await, json::array![48, 1, { disclose_me: true }, "com.example.slow"]);
+        assert_eq!(router.expect().await, json::array![49, 1, { mode: "killnowait" }]);
+        assert!(matches!(call.await.unwrap(), Err(Error::Timeout)));
+        assert!(lock(&client.handler).call_results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn interrupts_drop_the_running_procedure() {
+        let (client, mut router) = joined().await;
+        let (started, mut running) = mpsc::unbounded_channel();
+        let registered = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.register_async("com.example.slow", RegisterOptions::default(), move |_| {
+                    let started = started.clone();
+                    async move {
+                        let (alive, dropped) = tokio::sync::oneshot::channel::<()>();
+                        let _ = started.send(dropped);
+                        let _alive = alive;
+                        std::future::pending::<InvocationResult>().await
+                    }
+                }).await
+            }
+        });
+        router.expect().await;
+        router.reply("[65, 1, 100]").await;
+        registered.await.unwrap().unwrap();
+
+        router.reply("[68, 7, 100, {}]").await;
+        let dropped = running.recv().await.unwrap();
+        router.reply(r#"[69, 7, {"mode": "kill"}]"#).await;
+        assert_eq!(router.expect().await, json::array![8, 68, 7, {}, "wamp.error.canceled"]);
+        // The procedure's future, and with it the sender it captured, has been dropped.
+        assert!(dropped.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn panicking_procedures_answer_with_a_runtime_error() {
+        let (client, mut router) = joined().await;
+        let (failures, mut reported) = mpsc::unbounded_channel();
+        client.on(Events::Failure(Box::new(move |context, error| {
+            let _ = failures.send(error.to_string());
+            context
+        })));
+        let registered = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.register("com.example.fail", RegisterOptions::default(), Box::new(|_, _| panic!("out of order"))).await
+            }
+        });
+        router.expect().await;
+        router.reply("[65, 1, 100]").await;
+        registered.await.unwrap().unwrap();
+
+        router.reply("[68, 7, 100, {}]").await;
+        assert_eq!(router.expect().await, json::array![8, 68, 7, {}, "wamp.error.runtime_error", ["out of order"]]);
+        assert!(reported.recv().await.unwrap().contains("out of order"));
+    }
+
+    #[tokio::test]
+    async fn handlers_act_through_their_context() {
+        let (client, mut router) = joined().await;
+        let (streams, mut started) = mpsc::unbounded_channel();
+        let subscribed = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.subscribe("com.example.tick", SubscribeOptions::default(), Box::new(move |mut context, _| {
+                    context.set_key_ring(PrefixKeyRing::new().add("com.example", PayloadKey::new("tail", [3; 32])));
+                    let _ = streams.send(context.call_progressive("com.example.tail", JsonValue::Null, JsonValue::Null, CallOptions::default()));
+                    context
+                })).await
+            }
+        });
+        router.expect().await;
+        router.reply("[33, 1, 5]").await;
+        subscribed.await.unwrap().unwrap();
+
+        router.reply("[36, 5, 1, {}]").await;
+        let mut results = started.recv().await.unwrap().unwrap();
+        let call = router.expect().await;
+        assert_eq!((call[2]["receive_progress"].as_bool(), call[2]["ppt_keyid"].as_str()), (Some(true), Some("tail")));
+        let (mut details, mut args, mut kwargs) = (json::object! {}, json::array!["done"], JsonValue::Null);
+        crate::ppt::seal(&PayloadKey::new("tail", [3; 32]), &mut details, &mut args, &mut kwargs).unwrap();
+        router.reply(&json::array![50, call[1].clone(), details, args].dump()).await;
+        assert_eq!(results.next().await.unwrap().unwrap().args, json::array!["done"]);
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_frames_and_keeps_receiving() {
+        let (client, mut router) = joined().await;
+        let (failures, mut reported) = mpsc::unbounded_channel();
+        client.on(Events::Failure(Box::new(move |context, error| {
+            let _ = failures.send(error);
+            context
+        })));
+
+        router.reply("[36, 5").await;
+        assert!(matches!(reported.recv().await.unwrap(), Error::Serialization(_) | Error::JsonError(_)));
+        router.reply(r#"[999, "not a message"]"#).await;
+        assert!(matches!(reported.recv().await.unwrap(), Error::Serialization(_) | Error::JsonError(_)));
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, u32>("com.example.add", &(1, 2)).await }
+        });
+        let sent = router.expect().await;
+        router.reply(&format!("[50, {}, {{}}, [3]]", sent[1])).await;
+        assert_eq!(call.await.unwrap().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn leaving_fails_pending_requests_and_stops_the_loop() {
+        let (client, mut router) = joined().await;
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, u32>("com.example.add", &(1, 2)).await }
+        });
+        router.expect().await;
+
+        let left = tokio::spawn({
+            let client = client.clone();
+            async move { client.leave("wamp.close.close_realm").await }
+        });
+        assert_eq!(router.expect().await, json::array![6, {}, "wamp.close.close_realm"]);
+        router.reply(r#"[6, {}, "wamp.close.goodbye_and_out"]"#).await;
+
+        assert_eq!(left.await.unwrap().unwrap().reason, "wamp.close.goodbye_and_out");
+        assert!(matches!(call.await.unwrap(), Err(Error::SessionClosed)));
+        client.loop_messages().await;
+    }
+
+    #[tokio::test]
+    async fn closes_when_the_router_never_says_goodbye() {
+        let (client, mut router) = joined().await;
+        let left = tokio::spawn({
+            let client = client.clone();
+            async move { client.leave_with_timeout("wamp.close.close_realm", Duration::from_millis(10)).await }
+        });
+        assert_eq!(router.expect().await, json::array![6, {}, "wamp.close.close_realm"]);
+        assert!(matches!(left.await.unwrap(), Err(Error::Timeout)));
+        client.loop_messages().await;
+        assert!(matches!(client.call_typed::<_, u32>("com.example.add", &(1, 2)).await, Err(Error::SessionClosed)));
+    }
+
+    #[tokio::test]
+    async fn ends_the_session_after_aborting_a_protocol_violation() {
+        let (client, mut router) = joined().await;
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, u32>("com.example.add", &(1, 2)).await }
+        });
+        router.expect().await;
+
+        router.reply(r#"[1, "realm1", {}]"#).await;
+        let abort = router.expect().await;
+        assert_eq!((abort[0].as_u64(), abort[2].as_str()), (Some(3), Some("wamp.error.protocol_violation")));
+        assert!(matches!(call.await.unwrap(), Err(Error::SessionClosed)));
+        client.loop_messages().await;
     }
 
+    #[tokio::test]
+    async fn answers_a_goodbye_from_the_router() {
+        let (client, mut router) = joined().await;
+        router.reply(r#"[6, {}, "wamp.close.system_shutdown"]"#).await;
+        assert_eq!(router.expect().await, json::array![6, {}, "wamp.close.goodbye_and_out"]);
+        client.loop_messages().await;
+        assert!(matches!(client.call_typed::<_, u32>("com.example.add", &(1, 2)).await, Err(Error::SessionClosed)));
+    }
+
+    #[tokio::test]
+    async fn only_acknowledged_publications_wait_for_the_router() {
+        let (client, mut router) = joined().await;
+        assert_eq!(client.publish_typed("com.example.chat", &"hi", PublishOptions::default()).await.unwrap(), None);
+        assert_eq!(router.expect().await, json::array![16, 1, {}, "com.example.chat", ["hi"]]);
+        assert!(lock(&client.handler).publish_callbacks.is_empty());
+
+        let published = tokio::spawn({
+            let client = client.clone();
+            async move { client.publish_typed("com.example.chat", &"hi", PublishOptions::default().acknowledge()).await }
+        });
+        router.expect().await;
+        router.reply("[17, 2, 500]").await;
+        assert_eq!(published.await.unwrap().unwrap(), Some(500));
 
+        let refused = tokio::spawn({
+            let client = client.clone();
+            async move { client.publish_typed("com.example.chat", &"hi", PublishOptions::default().acknowledge()).await }
+        });
+        router.expect().await;
+        router.reply(r#"[8, 16, 3, {}, "wamp.error.not_authorized"]"#).await;
+        match refused.await.unwrap() {
+            Err(Error::Wamp(error)) => assert_eq!(error.uri, "wamp.error.not_authorized"),
+            other => panic!("unexpected reply {other:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn invocations_carry_the_called_procedure() {
+        let (client, mut router) = joined().await;
+        let registered = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.register_async("com.example", RegisterOptions::default().matching(Match::Prefix), |invocation| async move {
+                    Ok(Payload::new(json::array![invocation.details["procedure"].clone()], JsonValue::Null))
+                }).await
+            }
+        });
+        assert_eq!(router.expect().await, json::array![64, 1, { match: "prefix" }, "com.example"]);
+        router.reply("[65, 1, 100]").await;
+        registered.await.unwrap().unwrap();
+
+        router.reply(r#"[68, 7, 100, {"procedure": "com.example.add"}]"#).await;
+        assert_eq!(router.expect().await, json::array![70, 7, {}, ["com.example.add"]]);
+        router.reply("[68, 8, 100, {}]").await;
+        assert_eq!(router.expect().await, json::array![70, 8, {}, ["com.example"]]);
+    }
+
+    #[tokio::test]
+    async fn encrypts_payloads_end_to_end() {
+        let (client, mut router) = joined().await;
+        client.set_key_ring(PrefixKeyRing::new().add("com.example", PayloadKey::new("chat", [7; 32])));
+
+        client.publish_typed("com.example.chat", &"hi", PublishOptions::default()).await.unwrap();
+        let publish = router.expect().await;
+        assert_eq!((&publish[2]["ppt_scheme"], &publish[2]["ppt_keyid"]), (&"wamp".into(), &"chat".into()));
+        assert!(publish[4][0].as_str().unwrap().starts_with('\0'));
+
+        let (received, mut texts) = mpsc::unbounded_channel();
+        let subscribed = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.subscribe_typed("com.example.chat", SubscribeOptions::default(), move |context, text: Result<String, Error>| {
+                    let _ = received.send(text.unwrap());
+                    context
+                }).await
+            }
+        });
+        router.expect().await;
+        router.reply("[33, 2, 100]").await;
+        subscribed.await.unwrap().unwrap();
+
+        let mut event = json::array![36, 100, 1, publish[2].clone(), publish[4].clone()];
+        router.reply(&event.dump()).await;
+        event[3]["ppt_keyid"] = "unknown".into();
+        router.reply(&event.dump()).await;
+        router.reply(r#"[36, 100, 2, {}, ["plain"]]"#).await;
+        router.reply(&json::array![36, 100, 3, publish[2].clone(), publish[4].clone()].dump()).await;
+        assert_eq!(texts.recv().await.unwrap(), "hi");
+        assert_eq!(texts.recv().await.unwrap(), "hi");
+    }
+
+    #[tokio::test]
+    async fn encrypts_invocations_and_their_replies_end_to_end() {
+        let (client, mut router) = joined().await;
+        client.set_key_ring(PrefixKeyRing::new().add("com.example", PayloadKey::new("math", [9; 32])));
+        let registered = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.register("com.example.div", RegisterOptions::default(), Box::new(|context, invocation| {
+                    let (a, b) = (invocation.args[0].as_i64().unwrap(), invocation.args[1].as_i64().unwrap());
+                    if b == 0 {
+                        return (context, Err(ProcedureError { args: json::array!["division by zero"], ..ProcedureError::new("com.example.error") }));
+                    }
+                    (context, Ok(Payload::new(json::array![a / b], JsonValue::Null)))
+                })).await
+            }
+        });
+        router.expect().await;
+        router.reply("[65, 1, 100]").await;
+        registered.await.unwrap().unwrap();
+
+        // The router relays the sealed CALL as an INVOCATION, and the sealed reply back.
+        let quotient = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, i64>("com.example.div", &(6, 3)).await }
+        });
+        let call = router.expect().await;
+        assert_eq!(call[2]["ppt_keyid"].as_str(), Some("math"));
+        assert!(call[4][0].as_str().unwrap().starts_with('\0'));
+        router.reply(&json::array![68, 7, 100, call[2].clone(), call[4].clone()].dump()).await;
+        let reply = router.expect().await;
+        assert_eq!((reply[0].as_u64(), &reply[2]["ppt_keyid"]), (Some(70), &"math".into()));
+        assert!(reply[3][0].as_str().unwrap().starts_with('\0'));
+        router.reply(&json::array![50, call[1].clone(), reply[2].clone(), reply[3].clone()].dump()).await;
+        assert_eq!(quotient.await.unwrap().unwrap(), 2);
+
+        let failed = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, i64>("com.example.div", &(6, 0)).await }
+        });
+        let call = router.expect().await;
+        router.reply(&json::array![68, 8, 100, call[2].clone(), call[4].clone()].dump()).await;
+        let error = router.expect().await;
+        assert_eq!((error[0].as_u64(), &error[3]["ppt_keyid"], error[4].as_str()), (Some(8), &"math".into(), Some("com.example.error")));
+        assert!(error[5][0].as_str().unwrap().starts_with('\0'));
+        router.reply(&json::array![8, 48, call[1].clone(), error[3].clone(), error[4].clone(), error[5].clone()].dump()).await;
+        match failed.await.unwrap() {
+            Err(Error::Wamp(error)) => assert_eq!(error.args, json::array!["division by zero"]),
+            other => panic!("unexpected reply {other:?}")
+        }
+
+        // Unencrypted payloads for a URI with a key are rejected in both directions.
+        router.reply("[68, 9, 100, {}, [6, 3]]").await;
+        let refused = router.expect().await;
+        assert_eq!((refused[0].as_u64(), refused[2].as_u64(), refused[4].as_str()), (Some(8), Some(9), Some("wamp.error.invalid_payload")));
+        let unsealed = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<_, i64>("com.example.div", &(6, 3)).await }
+        });
+        let call = router.expect().await;
+        router.reply(&json::array![50, call[1].clone(), {}, [2]].dump()).await;
+        match unsealed.await.unwrap() {
+            Err(Error::Wamp(error)) => assert_eq!(error.uri, "wamp.error.invalid_payload"),
+            other => panic!("unexpected reply {other:?}")
+        }
+    }
 }
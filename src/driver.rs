@@ -0,0 +1,281 @@
+use std::{any::Any, panic::{self, AssertUnwindSafe}, sync::{Arc, Mutex}};
+
+use futures::StreamExt;
+use json::JsonValue;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use wamp_helpers::messages::Events as WampEvents;
+
+use crate::{
+    callback::{CallbackHandler, Payload, ProcedureError, Running, StartedInvocation, invocation_reply, lock},
+    error::Error,
+    ppt::PayloadKey,
+    reconnect::{Lifecycle, ReconnectPolicy},
+    serializer::Serializer,
+    session::SessionDetails,
+    transport::{BoxFuture, Message, Transport}
+};
+
+/// Opens a new connection to the router the session was first connected to.
+pub(crate) type Connector = Arc<dyn Fn() -> BoxFuture<'static, Result<Box<dyn Transport>, Error>> + Send + Sync>;
+
+/// Owns the transport of a session. Incoming messages are dispatched through the shared
+/// `CallbackHandler`, and messages queued by `WampClient` handles are written out.
+pub(crate) struct SessionDriver {
+    transport: Box<dyn Transport>,
+    serializer: Arc<dyn Serializer>,
+    outgoing: mpsc::UnboundedReceiver<JsonValue>,
+    /// Sending half of `outgoing`, for replies of async procedures.
+    replies: mpsc::UnboundedSender<JsonValue>,
+    handler: Arc<Mutex<CallbackHandler>>,
+    closed: watch::Sender<bool>,
+    reconnect: Option<(Connector, ReconnectPolicy)>,
+    /// Realm being joined again after a reconnect. Messages from handles are held back
+    /// until its WELCOME arrives.
+    rejoining: Option<String>,
+    shutdown: CancellationToken
+}
+
+impl SessionDriver {
+    pub(crate) fn new(
+        transport: Box<dyn Transport>,
+        serializer: Arc<dyn Serializer>,
+        outgoing: mpsc::UnboundedReceiver<JsonValue>,
+        replies: mpsc::UnboundedSender<JsonValue>,
+        handler: Arc<Mutex<CallbackHandler>>,
+        closed: watch::Sender<bool>,
+        reconnect: Option<(Connector, ReconnectPolicy)>
+    ) -> SessionDriver {
+        let shutdown = lock(&handler).shutdown.clone();
+        SessionDriver {
+            transport,
+            serializer,
+            outgoing,
+            replies,
+            handler,
+            closed,
+            reconnect,
+            rejoining: None,
+            shutdown
+        }
+    }
+
+    pub(crate) async fn run(mut self) {
+        loop {
+            tokio::select! {
+                message = self.transport.receive() => match message {
+                    Some(Ok(message)) => {
+                        if !self.receive(&message).await {
+                            break;
+                        }
+                    },
+                    Some(Err(error)) => {
+                        lock(&self.handler).report(error);
+                        if !self.reconnect().await {
+                            break;
+                        }
+                    },
+                    None => {
+                        if !self.reconnect().await {
+                            break;
+                        }
+                    }
+                },
+                Some(message) = self.outgoing.recv(), if self.rejoining.is_none() => {
+                    self.write_all(vec![message]).await;
+                },
+                _ = self.shutdown.cancelled() => break
+            }
+        }
+
+        let _ = self.transport.close().await;
+        let mut handler = lock(&self.handler);
+        handler.fail_pending();
+        handler.notify(Lifecycle::Closed);
+        let _ = self.closed.send(true);
+    }
+
+    /// Dispatches one message and writes out whatever the handlers queued. Unparsable
+    /// messages and panicking handlers are reported through `Events::Failure`, and an
+    /// INVOCATION whose procedure panicked is answered with `wamp.error.runtime_error`. Returns
+    /// false once the session is over: after GOODBYE, after the client aborted it for a
+    /// protocol violation, or when the router refused to rejoin.
+    async fn receive(&mut self, message: &Message) -> bool {
+        let parsed = self.serializer.deserialize(message)
+            .and_then(|message| WampEvents::parse_message(&message.dump()).map_err(Error::JsonError));
+        let event = match parsed {
+            Ok(event) => event,
+            Err(error) => {
+                let to_send = {
+                    let mut handler = lock(&self.handler);
+                    handler.report(error);
+                    std::mem::take(&mut handler.message_queue)
+                };
+                self.write_all(to_send).await;
+                return true;
+            }
+        };
+
+        let goodbye = matches!(event, WampEvents::Goodbye(_));
+        let (to_send, invocations, aborted) = {
+            let mut handler = lock(&self.handler);
+            let context = handler.context();
+            let dispatched = panic::catch_unwind(AssertUnwindSafe(|| handler.handle_event(event.clone(), context)));
+            match dispatched {
+                Ok(context) => handler.merge(context.messages),
+                Err(panic) => {
+                    let message = panic_message(panic);
+                    if let WampEvents::Invocation(invocation) = &event {
+                        handler.message_queue.extend(runtime_error(invocation.request, &message));
+                    }
+                    handler.report(Error::Handler(message));
+                }
+            }
+
+            if let Some(realm) = self.rejoining.take() {
+                match event {
+                    WampEvents::Welcome(welcome) => {
+                        handler.restore();
+                        handler.notify(Lifecycle::Rejoined(Box::new(SessionDetails::new(&realm, welcome))));
+                    },
+                    WampEvents::Abort(_) => return false,
+                    _ => self.rejoining = Some(realm)
+                }
+            }
+            let aborted = std::mem::take(&mut handler.aborted);
+            (std::mem::take(&mut handler.message_queue), std::mem::take(&mut handler.invocations), aborted)
+        };
+
+        self.write_all(to_send).await;
+        self.spawn_invocations(invocations);
+        !goodbye && !aborted
+    }
+
+    /// Runs invocations of async and streaming procedures on their own tasks, dropping them
+    /// once INTERRUPT cancels their token. A procedure that panics answers the caller with
+    /// `wamp.error.runtime_error` and is reported through `Events::Failure`.
+    fn spawn_invocations(&self, invocations: Vec<StartedInvocation>) {
+        for StartedInvocation { request, interrupted, running, key } in invocations {
+            let replies = self.replies.clone();
+            let handler = self.handler.clone();
+            tokio::spawn(async move {
+                let invocation = run_invocation(request, running, key, replies.clone());
+                let finished = tokio::spawn(async move {
+                    tokio::select! {
+                        _ = interrupted.cancelled() => (),
+                        _ = invocation => ()
+                    }
+                }).await;
+                lock(&handler).interruptible.remove(&request);
+                if let Err(error) = finished {
+                    let message = match error.try_into_panic() {
+                        Ok(panic) => panic_message(panic),
+                        Err(_) => "procedure was cancelled".to_string()
+                    };
+                    if let Some(reply) = runtime_error(request, &message) {
+                        let _ = replies.send(reply);
+                    }
+                    lock(&handler).report(Error::Handler(message));
+                }
+            });
+        }
+    }
+
+    /// Reconnects following the policy and queues the HELLO of the last join. Returns
+    /// false when the session has no policy, is being left or closed, or the policy gave up.
+    async fn reconnect(&mut self) -> bool {
+        let Some((connector, policy)) = self.reconnect.clone() else {
+            return false;
+        };
+        {
+            let mut handler = lock(&self.handler);
+            if handler.closing {
+                return false;
+            }
+            handler.fail_unrestored();
+            handler.notify(Lifecycle::Disconnected);
+        }
+
+        let mut attempt = 0;
+        self.transport = loop {
+            attempt += 1;
+            if policy.exhausted(attempt) {
+                return false;
+            }
+
+            let delay = policy.delay(attempt);
+            lock(&self.handler).notify(Lifecycle::Reconnecting { attempt, delay });
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => (),
+                _ = self.shutdown.cancelled() => return false
+            }
+
+            if let Ok(transport) = connector().await {
+                break transport;
+            }
+        };
+
+        let to_send = {
+            let mut handler = lock(&self.handler);
+            self.rejoining = handler.rejoin();
+            std::mem::take(&mut handler.message_queue)
+        };
+        self.write_all(to_send).await;
+        true
+    }
+
+    /// Serializes and writes messages. Failures are reported through `Events::Failure`.
+    async fn write_all(&mut self, to_send: Vec<JsonValue>) {
+        for message in to_send {
+            let written = match self.serializer.serialize(&message) {
+                Ok(message) => self.transport.send(message).await,
+                Err(error) => Err(error)
+            };
+            if let Err(error) = written {
+                lock(&self.handler).report(error);
+            }
+        }
+    }
+}
+
+/// Sends the YIELDs or the ERROR of one invocation to `replies`. A stream's last result
+/// is held back so it can be sent as the final YIELD.
+async fn run_invocation(request: u64, running: Running, key: Option<PayloadKey>, replies: mpsc::UnboundedSender<JsonValue>) {
+    let send = |result, progress| {
+        if let Ok(reply) = invocation_reply(request, result, progress, key.as_ref()) {
+            let _ = replies.send(reply);
+        }
+    };
+
+    match running {
+        Running::Future(result) => send(result.await, false),
+        Running::Stream { progress, mut results } => {
+            let mut last = None;
+            while let Some(result) = results.next().await {
+                match result {
+                    Ok(payload) => {
+                        if let Some(previous) = last.replace(payload) {
+                            if progress {
+                                send(Ok(previous), true);
+                            }
+                        }
+                    },
+                    Err(error) => return send(Err(error), false)
+                }
+            }
+            send(Ok(last.unwrap_or_else(|| Payload::new(JsonValue::Null, JsonValue::Null))), false);
+        }
+    }
+}
+
+/// ERROR answering the INVOCATION `request` of a procedure that panicked with `message`.
+fn runtime_error(request: u64, message: &str) -> Option<JsonValue> {
+    let error = ProcedureError { args: json::array![message], ..ProcedureError::new("wamp.error.runtime_error") };
+    invocation_reply(request, Err(error), false, None).ok()
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
@@ -1,9 +1,116 @@
+use std::fmt;
+
+use json::JsonValue;
 use websockets::WebSocketError;
 use wamp_helpers::error::Error as WampParseError;
+use wamp_helpers::messages::ErrorMessage;
 
 #[derive(Debug)]
 pub enum Error {
-    NoWebsocketError,
     WsError(WebSocketError),
-    JsonError(WampParseError)
-}
\ No newline at end of file
+    Io(std::io::Error),
+    /// The router refused the transport handshake.
+    Handshake(String),
+    /// A message could not be parsed or serialized.
+    JsonError(WampParseError),
+    /// A message could not be encoded or decoded by the session's serializer.
+    Serialization(String),
+    /// Arguments or a result did not match the Rust type they were decoded into.
+    Payload(serde_json::Error),
+    /// An end-to-end encrypted payload could not be sealed or opened.
+    Encryption(String),
+    /// The router sent something the protocol does not allow at this point.
+    Protocol(String),
+    /// The router answered a request with ERROR.
+    Wamp(Box<WampError>),
+    /// The router sent ABORT, or the client aborted while joining.
+    Abort { reason: String, details: JsonValue },
+    /// No authenticator could answer the router's CHALLENGE.
+    Authentication(String),
+    /// A request did not get a reply in time.
+    Timeout,
+    /// The session ended before the request completed.
+    SessionClosed,
+    /// An application handler panicked while handling a message.
+    Handler(String)
+}
+
+/// Error URI and payload of an ERROR message.
+#[derive(Debug, Clone)]
+pub struct WampError {
+    pub uri: String,
+    pub details: JsonValue,
+    pub args: JsonValue,
+    pub kwargs: JsonValue
+}
+
+impl From<ErrorMessage> for WampError {
+    fn from(error: ErrorMessage) -> Self {
+        WampError { uri: error.error, details: error.details, args: error.args, kwargs: error.kwargs }
+    }
+}
+
+impl From<ErrorMessage> for Error {
+    fn from(error: ErrorMessage) -> Self {
+        Error::Wamp(Box::new(error.into()))
+    }
+}
+
+impl From<WebSocketError> for Error {
+    fn from(error: WebSocketError) -> Self {
+        Error::WsError(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<WampParseError> for Error {
+    fn from(error: WampParseError) -> Self {
+        Error::JsonError(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WsError(error) => write!(f, "websocket error: {error}"),
+            Error::Io(error) => write!(f, "i/o error: {error}"),
+            Error::Handshake(reason) => write!(f, "transport handshake failed: {reason}"),
+            Error::JsonError(error) => write!(f, "invalid WAMP message: {error:?}"),
+            Error::Serialization(message) => write!(f, "serialization failed: {message}"),
+            Error::Payload(error) => write!(f, "unexpected payload: {error}"),
+            Error::Encryption(message) => write!(f, "payload encryption failed: {message}"),
+            Error::Protocol(message) => write!(f, "protocol violation: {message}"),
+            Error::Wamp(error) => {
+                write!(f, "{}", error.uri)?;
+                if !error.args.is_null() && !error.args.is_empty() {
+                    write!(f, " {}", error.args)?;
+                }
+                if !error.kwargs.is_null() && !error.kwargs.is_empty() {
+                    write!(f, " {}", error.kwargs)?;
+                }
+                Ok(())
+            },
+            Error::Abort { reason, details } => write!(f, "session aborted: {reason} {details}"),
+            Error::Authentication(message) => write!(f, "authentication failed: {message}"),
+            Error::Timeout => write!(f, "request timed out"),
+            Error::SessionClosed => write!(f, "session closed"),
+            Error::Handler(message) => write!(f, "handler panicked: {message}")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::WsError(error) => Some(error),
+            Error::Io(error) => Some(error),
+            Error::Payload(error) => Some(error),
+            _ => None
+        }
+    }
+}
@@ -1,6 +1,22 @@
+extern crate self as wamp_rs;
+
+pub use wamp_rs_macros::{procedure, service};
+
+pub mod auth;
 pub mod client;
 pub mod error;
+pub mod meta;
+pub mod options;
+pub mod ppt;
+pub mod rawsocket;
 pub mod callback;
+mod driver;
+pub mod reconnect;
+pub mod serializer;
+pub mod service;
+pub mod session;
+pub mod transport;
+pub mod typed;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -0,0 +1,271 @@
+use json::JsonValue;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    callback::{ReceivedEvent, Subscription},
+    client::{Context, WampClient},
+    error::Error,
+    options::{InvokePolicy, Match, SubscribeOptions},
+    typed::{argument, from_payload}
+};
+
+/// Router meta API: introspection of sessions, subscriptions and registrations through
+/// the `wamp.session.*`, `wamp.subscription.*` and `wamp.registration.*` procedures and
+/// events. Routers have to enable it, usually only for some roles.
+#[derive(Clone)]
+pub struct Meta {
+    client: WampClient
+}
+
+/// A session joined to the realm, as `wamp.session.get` and `wamp.session.on_join` report it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SessionInfo {
+    pub session: u64,
+    pub authid: Option<String>,
+    pub authrole: Option<String>,
+    pub authmethod: Option<String>,
+    pub authprovider: Option<String>
+}
+
+/// Ids of the subscriptions or registrations of a realm, by match policy.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MatchLists {
+    pub exact: Vec<u64>,
+    pub prefix: Vec<u64>,
+    pub wildcard: Vec<u64>
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SubscriptionInfo {
+    pub id: u64,
+    /// When the subscription was created, as an ISO 8601 timestamp.
+    pub created: String,
+    pub uri: String,
+    #[serde(rename = "match", deserialize_with = "match_policy")]
+    pub matching: Match
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RegistrationInfo {
+    pub id: u64,
+    /// When the registration was created, as an ISO 8601 timestamp.
+    pub created: String,
+    pub uri: String,
+    #[serde(rename = "match", deserialize_with = "match_policy")]
+    pub matching: Match,
+    #[serde(deserialize_with = "invoke_policy")]
+    pub invoke: InvokePolicy
+}
+
+/// Meta event published by the router, decoded from its topic and arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaEvent {
+    SessionJoined(SessionInfo),
+    SessionLeft { session: u64, authid: Option<String>, authrole: Option<String> },
+    SubscriptionCreated { session: u64, subscription: SubscriptionInfo },
+    Subscribed { session: u64, subscription: u64 },
+    Unsubscribed { session: u64, subscription: u64 },
+    SubscriptionDeleted { session: u64, subscription: u64 },
+    RegistrationCreated { session: u64, registration: RegistrationInfo },
+    Registered { session: u64, registration: u64 },
+    Unregistered { session: u64, registration: u64 },
+    RegistrationDeleted { session: u64, registration: u64 }
+}
+
+impl MetaEvent {
+    fn decode(received: &ReceivedEvent) -> Result<MetaEvent, Error> {
+        let args = &received.event.args;
+        let ids = || from_payload::<(u64, u64)>(args, &JsonValue::Null);
+        Ok(match received.topic.as_str() {
+            "wamp.session.on_join" => MetaEvent::SessionJoined(argument(args, &JsonValue::Null, 0, "details")?),
+            "wamp.session.on_leave" => MetaEvent::SessionLeft {
+                session: argument(args, &JsonValue::Null, 0, "session")?,
+                authid: argument(args, &JsonValue::Null, 1, "authid")?,
+                authrole: argument(args, &JsonValue::Null, 2, "authrole")?
+            },
+            "wamp.subscription.on_create" => MetaEvent::SubscriptionCreated {
+                session: argument(args, &JsonValue::Null, 0, "session")?,
+                subscription: argument(args, &JsonValue::Null, 1, "details")?
+            },
+            "wamp.subscription.on_subscribe" => {
+                let (session, subscription) = ids()?;
+                MetaEvent::Subscribed { session, subscription }
+            },
+            "wamp.subscription.on_unsubscribe" => {
+                let (session, subscription) = ids()?;
+                MetaEvent::Unsubscribed { session, subscription }
+            },
+            "wamp.subscription.on_delete" => {
+                let (session, subscription) = ids()?;
+                MetaEvent::SubscriptionDeleted { session, subscription }
+            },
+            "wamp.registration.on_create" => MetaEvent::RegistrationCreated {
+                session: argument(args, &JsonValue::Null, 0, "session")?,
+                registration: argument(args, &JsonValue::Null, 1, "details")?
+            },
+            "wamp.registration.on_register" => {
+                let (session, registration) = ids()?;
+                MetaEvent::Registered { session, registration }
+            },
+            "wamp.registration.on_unregister" => {
+                let (session, registration) = ids()?;
+                MetaEvent::Unregistered { session, registration }
+            },
+            "wamp.registration.on_delete" => {
+                let (session, registration) = ids()?;
+                MetaEvent::RegistrationDeleted { session, registration }
+            },
+            other => return Err(Error::Protocol(format!("{other} is not a meta event")))
+        })
+    }
+}
+
+impl Meta {
+    pub(crate) fn new(client: WampClient) -> Meta {
+        Meta { client }
+    }
+
+    /// Number of sessions, counting only those with one of `authroles` unless it is empty.
+    pub async fn session_count(&self, authroles: &[&str]) -> Result<u64, Error> {
+        if authroles.is_empty() {
+            return self.client.call_typed("wamp.session.count", &()).await;
+        }
+        self.client.call_typed("wamp.session.count", &(authroles,)).await
+    }
+
+    /// Ids of the sessions, only those with one of `authroles` unless it is empty.
+    pub async fn session_list(&self, authroles: &[&str]) -> Result<Vec<u64>, Error> {
+        if authroles.is_empty() {
+            return self.client.call_typed("wamp.session.list", &()).await;
+        }
+        self.client.call_typed("wamp.session.list", &(authroles,)).await
+    }
+
+    pub async fn session_get(&self, session: u64) -> Result<SessionInfo, Error> {
+        self.client.call_typed("wamp.session.get", &(session,)).await
+    }
+
+    pub async fn subscription_list(&self) -> Result<MatchLists, Error> {
+        self.client.call_typed("wamp.subscription.list", &()).await
+    }
+
+    /// Subscription for `topic` with the `matching` policy, if any session has one.
+    pub async fn subscription_lookup(&self, topic: &str, matching: Match) -> Result<Option<u64>, Error> {
+        let options = serde_json::json!({ "match": matching.as_str() });
+        self.client.call_typed("wamp.subscription.lookup", &(topic, options)).await
+    }
+
+    /// Subscriptions an event published to `topic` would be delivered to.
+    pub async fn subscription_match(&self, topic: &str) -> Result<Vec<u64>, Error> {
+        let matched: Option<Vec<u64>> = self.client.call_typed("wamp.subscription.match", &(topic,)).await?;
+        Ok(matched.unwrap_or_default())
+    }
+
+    pub async fn subscription_get(&self, subscription: u64) -> Result<SubscriptionInfo, Error> {
+        self.client.call_typed("wamp.subscription.get", &(subscription,)).await
+    }
+
+    pub async fn subscription_list_subscribers(&self, subscription: u64) -> Result<Vec<u64>, Error> {
+        self.client.call_typed("wamp.subscription.list_subscribers", &(subscription,)).await
+    }
+
+    pub async fn subscription_count_subscribers(&self, subscription: u64) -> Result<u64, Error> {
+        self.client.call_typed("wamp.subscription.count_subscribers", &(subscription,)).await
+    }
+
+    pub async fn registration_list(&self) -> Result<MatchLists, Error> {
+        self.client.call_typed("wamp.registration.list", &()).await
+    }
+
+    /// Registration for `procedure` with the `matching` policy, if any session has one.
+    pub async fn registration_lookup(&self, procedure: &str, matching: Match) -> Result<Option<u64>, Error> {
+        let options = serde_json::json!({ "match": matching.as_str() });
+        self.client.call_typed("wamp.registration.lookup", &(procedure, options)).await
+    }
+
+    /// Registration a call to `procedure` would be routed to.
+    pub async fn registration_match(&self, procedure: &str) -> Result<Option<u64>, Error> {
+        self.client.call_typed("wamp.registration.match", &(procedure,)).await
+    }
+
+    pub async fn registration_get(&self, registration: u64) -> Result<RegistrationInfo, Error> {
+        self.client.call_typed("wamp.registration.get", &(registration,)).await
+    }
+
+    pub async fn registration_list_callees(&self, registration: u64) -> Result<Vec<u64>, Error> {
+        self.client.call_typed("wamp.registration.list_callees", &(registration,)).await
+    }
+
+    pub async fn registration_count_callees(&self, registration: u64) -> Result<u64, Error> {
+        self.client.call_typed("wamp.registration.count_callees", &(registration,)).await
+    }
+
+    /// Subscribes `on_event` to meta events, such as `wamp.session.on_join`, or with a
+    /// prefix match to all of `wamp.session`. Events that do not decode reach `on_event`
+    /// as an error.
+    pub async fn subscribe<F>(&self, topic: &str, options: SubscribeOptions, on_event: F) -> Result<Subscription, Error>
+    where F: Fn(Context, Result<MetaEvent, Error>) -> Context + Send + Sync + 'static {
+        self.client.subscribe(topic, options, Box::new(move |context, received| on_event(context, MetaEvent::decode(&received)))).await
+    }
+}
+
+fn match_policy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Match, D::Error> {
+    Ok(Match::from_name(&String::deserialize(deserializer)?))
+}
+
+fn invoke_policy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<InvokePolicy, D::Error> {
+    Ok(InvokePolicy::from_name(&String::deserialize(deserializer)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{serializer::Json, transport::loopback};
+
+    #[tokio::test]
+    async fn calls_meta_procedures_and_decodes_meta_events() {
+        let (transport, mut router) = loopback();
+        let meta = WampClient::with_transport(transport, Json).meta();
+        let lookups = tokio::spawn({
+            let meta = meta.clone();
+            async move {
+                let count = meta.session_count(&["admin"]).await?;
+                let registration = meta.registration_get(5).await?;
+                let lookup = meta.subscription_lookup("com.example", Match::Prefix).await?;
+                Ok::<_, Error>((count, registration, lookup))
+            }
+        });
+
+        assert_eq!(router.answer("[50, 1, {}, [2]]").await, json::array![48, 1, {}, "wamp.session.count", [["admin"]]]);
+        let get = router.answer(r#"[50, 2, {}, [{"id": 5, "created": "2026-01-01T00:00:00Z", "uri": "com.example", "match": "prefix", "invoke": "roundrobin"}]]"#).await;
+        assert_eq!(get[4], json::array![5]);
+        let lookup = router.answer("[50, 3, {}, [null]]").await;
+        assert_eq!(lookup[4], json::array!["com.example", { match: "prefix" }]);
+
+        let (count, registration, lookup) = lookups.await.unwrap().unwrap();
+        assert_eq!((count, registration.matching, registration.invoke, lookup), (2, Match::Prefix, InvokePolicy::RoundRobin, None));
+
+        let (events, mut received) = mpsc::unbounded_channel();
+        let subscribed = tokio::spawn({
+            let meta = meta.clone();
+            async move {
+                meta.subscribe("wamp.session", SubscribeOptions::prefix(), move |context, event| {
+                    let _ = events.send(event.unwrap());
+                    context
+                }).await
+            }
+        });
+        router.answer("[33, 4, 100]").await;
+        subscribed.await.unwrap().unwrap();
+
+        router.reply(r#"[36, 100, 1, {"topic": "wamp.session.on_leave"}, [9, "joe", "user"]]"#).await;
+        assert_eq!(received.recv().await.unwrap(), MetaEvent::SessionLeft {
+            session: 9,
+            authid: Some("joe".to_string()),
+            authrole: Some("user".to_string())
+        });
+    }
+}
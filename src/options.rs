@@ -0,0 +1,347 @@
+use std::time::Duration;
+
+use json::JsonValue;
+
+/// How a subscription topic or registration procedure is matched against URIs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Match {
+    #[default]
+    Exact,
+    Prefix,
+    Wildcard
+}
+
+impl Match {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Match::Exact => "exact",
+            Match::Prefix => "prefix",
+            Match::Wildcard => "wildcard"
+        }
+    }
+
+    /// Reads the `match` option of a SUBSCRIBE or REGISTER.
+    pub(crate) fn from_options(options: &JsonValue) -> Match {
+        Match::from_name(options["match"].as_str().unwrap_or_default())
+    }
+
+    pub(crate) fn from_name(name: &str) -> Match {
+        match name {
+            "prefix" => Match::Prefix,
+            "wildcard" => Match::Wildcard,
+            _ => Match::Exact
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscribeOptions {
+    pub matching: Match
+}
+
+impl SubscribeOptions {
+    pub fn prefix() -> SubscribeOptions {
+        SubscribeOptions { matching: Match::Prefix }
+    }
+
+    pub fn wildcard() -> SubscribeOptions {
+        SubscribeOptions { matching: Match::Wildcard }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut options = json::object! {};
+        if self.matching != Match::Exact {
+            options["match"] = self.matching.as_str().into();
+        }
+        options
+    }
+}
+
+/// Which callee the router invokes when several sessions register the same procedure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvokePolicy {
+    /// Only one registration is allowed; the router refuses others.
+    #[default]
+    Single,
+    RoundRobin,
+    Random,
+    First,
+    Last
+}
+
+impl InvokePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InvokePolicy::Single => "single",
+            InvokePolicy::RoundRobin => "roundrobin",
+            InvokePolicy::Random => "random",
+            InvokePolicy::First => "first",
+            InvokePolicy::Last => "last"
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> InvokePolicy {
+        match name {
+            "roundrobin" => InvokePolicy::RoundRobin,
+            "random" => InvokePolicy::Random,
+            "first" => InvokePolicy::First,
+            "last" => InvokePolicy::Last,
+            _ => InvokePolicy::Single
+        }
+    }
+}
+
+/// Options of a REGISTER. Defaults are left out of the message.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RegisterOptions {
+    pub invoke: InvokePolicy,
+    pub matching: Match
+}
+
+impl RegisterOptions {
+    pub fn invoke(mut self, invoke: InvokePolicy) -> RegisterOptions {
+        self.invoke = invoke;
+        self
+    }
+
+    pub fn matching(mut self, matching: Match) -> RegisterOptions {
+        self.matching = matching;
+        self
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut options = json::object! {};
+        if self.invoke != InvokePolicy::Single {
+            options["invoke"] = self.invoke.as_str().into();
+        }
+        if self.matching != Match::Exact {
+            options["match"] = self.matching.as_str().into();
+        }
+        options
+    }
+}
+
+/// Options of a CALL. Every option is left out of the message until it is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CallOptions {
+    /// Ask the router to cancel the call once it has run this long.
+    pub timeout: Option<Duration>,
+    /// Ask the router to disclose the caller's session to the callee.
+    pub disclose_me: bool
+}
+
+impl CallOptions {
+    pub fn timeout(mut self, timeout: Duration) -> CallOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn disclose_me(mut self) -> CallOptions {
+        self.disclose_me = true;
+        self
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut options = json::object! {};
+        if let Some(timeout) = self.timeout {
+            options["timeout"] = (timeout.as_millis() as u64).into();
+        }
+        if self.disclose_me {
+            options["disclose_me"] = true.into();
+        }
+        options
+    }
+}
+
+/// Options of a PUBLISH. Every option is left out of the message until it is set, so the
+/// router applies its defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PublishOptions {
+    /// Ask the router for PUBLISHED, which carries the publication id.
+    pub acknowledge: bool,
+    /// Whether the publisher receives its own event. Routers exclude it by default.
+    pub exclude_me: Option<bool>,
+    /// Sessions that must not receive the event.
+    pub exclude: Vec<u64>,
+    pub exclude_authid: Vec<String>,
+    pub exclude_authrole: Vec<String>,
+    /// When not empty, only these sessions may receive the event.
+    pub eligible: Vec<u64>,
+    pub eligible_authid: Vec<String>,
+    pub eligible_authrole: Vec<String>,
+    /// Ask the router to disclose the publisher's session to subscribers.
+    pub disclose_me: bool
+}
+
+impl PublishOptions {
+    pub fn acknowledge(mut self) -> PublishOptions {
+        self.acknowledge = true;
+        self
+    }
+
+    pub fn exclude_me(mut self, exclude_me: bool) -> PublishOptions {
+        self.exclude_me = Some(exclude_me);
+        self
+    }
+
+    pub fn exclude(mut self, sessions: impl IntoIterator<Item = u64>) -> PublishOptions {
+        self.exclude.extend(sessions);
+        self
+    }
+
+    pub fn exclude_authid(mut self, authids: impl IntoIterator<Item = impl Into<String>>) -> PublishOptions {
+        self.exclude_authid.extend(authids.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn exclude_authrole(mut self, authroles: impl IntoIterator<Item = impl Into<String>>) -> PublishOptions {
+        self.exclude_authrole.extend(authroles.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn eligible(mut self, sessions: impl IntoIterator<Item = u64>) -> PublishOptions {
+        self.eligible.extend(sessions);
+        self
+    }
+
+    pub fn eligible_authid(mut self, authids: impl IntoIterator<Item = impl Into<String>>) -> PublishOptions {
+        self.eligible_authid.extend(authids.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn eligible_authrole(mut self, authroles: impl IntoIterator<Item = impl Into<String>>) -> PublishOptions {
+        self.eligible_authrole.extend(authroles.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn disclose_me(mut self) -> PublishOptions {
+        self.disclose_me = true;
+        self
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut options = json::object! {};
+        if self.acknowledge {
+            options["acknowledge"] = true.into();
+        }
+        if let Some(exclude_me) = self.exclude_me {
+            options["exclude_me"] = exclude_me.into();
+        }
+        let lists = [
+            ("exclude_authid", &self.exclude_authid),
+            ("exclude_authrole", &self.exclude_authrole),
+            ("eligible_authid", &self.eligible_authid),
+            ("eligible_authrole", &self.eligible_authrole)
+        ];
+        for (key, values) in lists {
+            if !values.is_empty() {
+                options[key] = values.clone().into();
+            }
+        }
+        if !self.exclude.is_empty() {
+            options["exclude"] = self.exclude.clone().into();
+        }
+        if !self.eligible.is_empty() {
+            options["eligible"] = self.eligible.clone().into();
+        }
+        if self.disclose_me {
+            options["disclose_me"] = true.into();
+        }
+        options
+    }
+}
+
+/// How the router treats a call that is being canceled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CancelMode {
+    /// Stop waiting for the call without telling the callee.
+    Skip,
+    /// Interrupt the callee and wait for it to acknowledge.
+    #[default]
+    Kill,
+    /// Interrupt the callee without waiting for it.
+    KillNoWait
+}
+
+impl CancelMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CancelMode::Skip => "skip",
+            CancelMode::Kill => "kill",
+            CancelMode::KillNoWait => "killnowait"
+        }
+    }
+}
+
+/// Past events `WampClient::subscribe_with_history` fetches with
+/// `wamp.subscription.get_events(subscription, limit)`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum History {
+    /// The last `n` events.
+    Last(u64),
+    /// Of the last `limit` events, those published after `timestamp`. The router has no
+    /// such filter, so it is applied to the fetched events' `timestamp`, which is compared
+    /// as a string and has to be an ISO 8601 UTC timestamp in the router's format.
+    Since { timestamp: String, limit: u64 }
+}
+
+impl History {
+    /// Number of events fetched.
+    pub(crate) fn limit(&self) -> u64 {
+        match self {
+            History::Last(limit) | History::Since { limit, .. } => *limit
+        }
+    }
+
+    /// Whether an event of the fetched history is one of those asked for.
+    pub(crate) fn includes(&self, entry: &JsonValue) -> bool {
+        match self {
+            History::Last(_) => true,
+            History::Since { timestamp, .. } => entry["timestamp"].as_str().is_some_and(|published| published > timestamp.as_str())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_match_is_left_out_of_options() {
+        assert_eq!(SubscribeOptions::default().to_json(), json::object! {});
+        assert_eq!(SubscribeOptions::wildcard().to_json(), json::object! { match: "wildcard" });
+        assert_eq!(Match::from_options(&SubscribeOptions::prefix().to_json()), Match::Prefix);
+    }
+
+    #[test]
+    fn register_options_leave_out_defaults() {
+        assert_eq!(RegisterOptions::default().to_json(), json::object! {});
+        let options = RegisterOptions::default().invoke(InvokePolicy::RoundRobin).matching(Match::Prefix);
+        assert_eq!(options.to_json(), json::object! { invoke: "roundrobin", match: "prefix" });
+    }
+
+    #[test]
+    fn call_options_only_carry_what_was_set() {
+        assert_eq!(CallOptions::default().to_json(), json::object! {});
+        let options = CallOptions::default().timeout(Duration::from_secs(2)).disclose_me();
+        assert_eq!(options.to_json(), json::object! { timeout: 2000, disclose_me: true });
+    }
+
+    #[test]
+    fn publish_options_only_carry_what_was_set() {
+        assert_eq!(PublishOptions::default().to_json(), json::object! {});
+        let options = PublishOptions::default()
+            .acknowledge()
+            .exclude_me(false)
+            .exclude([7])
+            .eligible_authrole(["admin"])
+            .disclose_me();
+        assert_eq!(options.to_json(), json::object! {
+            acknowledge: true,
+            exclude_me: false,
+            eligible_authrole: ["admin"],
+            exclude: [7],
+            disclose_me: true
+        });
+    }
+}
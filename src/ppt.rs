@@ -0,0 +1,140 @@
+use crypto_secretbox::{aead::{Aead, AeadCore, KeyInit, OsRng}, Key, Nonce, XSalsa20Poly1305};
+use json::JsonValue;
+
+use crate::{error::Error, serializer::{binary, binary_string}};
+
+/// `ppt_scheme` of payloads encrypted end to end by WAMP clients.
+pub const SCHEME_WAMP: &str = "wamp";
+
+/// `ppt_cipher` of payloads sealed with XSalsa20-Poly1305.
+pub const CIPHER_XSALSA20POLY1305: &str = "xsalsa20poly1305";
+
+const NONCE_LENGTH: usize = 24;
+
+/// Secret key for XSalsa20-Poly1305, announced to the receiver by its id in `ppt_keyid`.
+#[derive(Clone)]
+pub struct PayloadKey {
+    pub id: String,
+    key: Key
+}
+
+impl PayloadKey {
+    pub fn new(id: &str, key: [u8; 32]) -> PayloadKey {
+        PayloadKey { id: id.to_string(), key: key.into() }
+    }
+}
+
+/// Keys for end-to-end encrypted payloads. Payloads sent to a URI without a key go out
+/// in the clear.
+pub trait KeyRing: Send + Sync {
+    /// Key to encrypt payloads sent to the topic or procedure `uri`.
+    fn key_for(&self, uri: &str) -> Option<PayloadKey>;
+
+    /// Key a received payload was encrypted with.
+    fn key(&self, id: &str) -> Option<PayloadKey>;
+}
+
+/// Key ring choosing the key of the longest URI prefix that matches.
+#[derive(Clone, Default)]
+pub struct PrefixKeyRing {
+    keys: Vec<(String, PayloadKey)>
+}
+
+impl PrefixKeyRing {
+    pub fn new() -> PrefixKeyRing {
+        PrefixKeyRing::default()
+    }
+
+    /// Encrypts payloads for URIs starting with `prefix` with `key`.
+    pub fn add(mut self, prefix: &str, key: PayloadKey) -> PrefixKeyRing {
+        self.keys.push((prefix.to_string(), key));
+        self
+    }
+}
+
+impl KeyRing for PrefixKeyRing {
+    fn key_for(&self, uri: &str) -> Option<PayloadKey> {
+        self.keys.iter()
+            .filter(|(prefix, _)| uri.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, key)| key.clone())
+    }
+
+    fn key(&self, id: &str) -> Option<PayloadKey> {
+        self.keys.iter().find(|(_, key)| key.id == id).map(|(_, key)| key.clone())
+    }
+}
+
+/// Replaces `args` and `kwargs` with their encryption under `key`, sent as the single
+/// binary argument of Payload Passthru Mode, and announces the scheme in `options`.
+pub(crate) fn seal(key: &PayloadKey, options: &mut JsonValue, args: &mut JsonValue, kwargs: &mut JsonValue) -> Result<(), Error> {
+    let plaintext = json::object! { args: args.take(), kwargs: kwargs.take() }.dump();
+    let nonce = XSalsa20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XSalsa20Poly1305::new(&key.key)
+        .encrypt(&nonce, plaintext.as_bytes())
+        .map_err(|_| Error::Encryption("payload could not be sealed".to_string()))?;
+
+    *args = json::array![binary_string(&[nonce.as_slice(), &ciphertext].concat())];
+    options["ppt_scheme"] = SCHEME_WAMP.into();
+    options["ppt_serializer"] = "json".into();
+    options["ppt_cipher"] = CIPHER_XSALSA20POLY1305.into();
+    options["ppt_keyid"] = key.id.clone().into();
+    Ok(())
+}
+
+/// Decrypts `args` and `kwargs` when `details` announce an end-to-end encrypted payload.
+/// Other payloads, including passthru payloads of other schemes, are left alone.
+pub(crate) fn open(key_ring: Option<&dyn KeyRing>, details: &JsonValue, args: &mut JsonValue, kwargs: &mut JsonValue) -> Result<(), Error> {
+    if details["ppt_scheme"].as_str() != Some(SCHEME_WAMP) {
+        return Ok(());
+    }
+    let failed = |reason: String| Error::Encryption(reason);
+    if details["ppt_cipher"].as_str() != Some(CIPHER_XSALSA20POLY1305) || details["ppt_serializer"].as_str() != Some("json") {
+        return Err(failed(format!("unsupported cipher {} with serializer {}", details["ppt_cipher"], details["ppt_serializer"])));
+    }
+
+    let key_id = details["ppt_keyid"].as_str().unwrap_or_default();
+    let key = key_ring.and_then(|ring| ring.key(key_id)).ok_or_else(|| failed(format!("no key {key_id}")))?;
+    let sealed = args[0].as_str()
+        .and_then(binary)
+        .filter(|sealed| sealed.len() > NONCE_LENGTH)
+        .ok_or_else(|| failed("payload is not a sealed binary".to_string()))?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let plaintext = XSalsa20Poly1305::new(&key.key)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| failed(format!("payload does not open with key {key_id}")))?;
+    let payload = std::str::from_utf8(&plaintext).ok()
+        .and_then(|text| json::parse(text).ok())
+        .ok_or_else(|| failed("sealed payload is not JSON".to_string()))?;
+
+    *args = payload["args"].clone();
+    *kwargs = payload["kwargs"].clone();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_payloads_open_with_the_same_key() {
+        let ring = PrefixKeyRing::new()
+            .add("com.example", PayloadKey::new("general", [1; 32]))
+            .add("com.example.secret", PayloadKey::new("secret", [2; 32]));
+        let key = ring.key_for("com.example.secret.chat").unwrap();
+        assert_eq!(key.id, "secret");
+        assert!(ring.key_for("org.example").is_none());
+
+        let (mut options, mut args, mut kwargs) = (json::object! {}, json::array!["hi"], json::object! { to: "joe" });
+        seal(&key, &mut options, &mut args, &mut kwargs).unwrap();
+        assert_eq!((args.len(), &kwargs), (1, &JsonValue::Null));
+        assert_eq!(options["ppt_keyid"], "secret");
+
+        let mut tampered = args.clone();
+        tampered[0] = binary_string(&[0; 64]);
+        assert!(matches!(open(Some(&ring), &options, &mut tampered, &mut JsonValue::Null), Err(Error::Encryption(_))));
+
+        open(Some(&ring), &options, &mut args, &mut kwargs).unwrap();
+        assert_eq!((args, kwargs), (json::array!["hi"], json::object! { to: "joe" }));
+    }
+}
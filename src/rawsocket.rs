@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, Mutex}
+};
+
+use crate::{error::Error, transport::{BoxFuture, Message, Transport}};
+
+const MAGIC: u8 = 0x7F;
+
+const FRAME_MESSAGE: u8 = 0;
+const FRAME_PING: u8 = 1;
+const FRAME_PONG: u8 = 2;
+
+/// Longest message the handshake can announce, 2^24 bytes.
+pub const MAX_MESSAGE_LENGTH: u32 = 1 << 24;
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+/// WAMP over RawSocket: a 4-byte handshake, then messages framed by a 4-byte header
+/// holding the frame type and a 24-bit length.
+pub struct RawSocketTransport {
+    writer: Writer,
+    messages: mpsc::UnboundedReceiver<Result<Message, Error>>,
+    /// Longest message the router accepts, as negotiated in the handshake.
+    max_send_length: u32
+}
+
+impl RawSocketTransport {
+    /// Connects to a `tcp://host:port` or `unix:///path` endpoint and negotiates the
+    /// handshake for the serializer with `serializer_id`, announcing `max_message_length`
+    /// as the longest message this side accepts.
+    pub async fn connect(url: &str, serializer_id: u8, max_message_length: u32) -> Result<RawSocketTransport, Error> {
+        if let Some(address) = url.strip_prefix("tcp://") {
+            let stream = TcpStream::connect(address).await?;
+            stream.set_nodelay(true)?;
+            return RawSocketTransport::handshake(stream, serializer_id, max_message_length).await;
+        }
+        #[cfg(unix)]
+        if let Some(path) = url.strip_prefix("unix://") {
+            let stream = tokio::net::UnixStream::connect(path).await?;
+            return RawSocketTransport::handshake(stream, serializer_id, max_message_length).await;
+        }
+        Err(Error::Handshake(format!("unsupported rawsocket url {url}")))
+    }
+
+    /// Negotiates the handshake over an already connected stream.
+    pub async fn handshake<S>(mut stream: S, serializer_id: u8, max_message_length: u32) -> Result<RawSocketTransport, Error>
+    where S: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+        let exponent = length_exponent(max_message_length);
+        stream.write_all(&[MAGIC, (exponent << 4) | serializer_id, 0, 0]).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != MAGIC {
+            return Err(Error::Handshake("peer is not a rawsocket router".to_string()));
+        }
+        match reply[1] & 0x0F {
+            0 => return Err(Error::Handshake(handshake_error(reply[1] >> 4).to_string())),
+            id if id == serializer_id => (),
+            other => return Err(Error::Handshake(format!("router picked serializer {other}")))
+        }
+        let max_send_length = 1 << (9 + (reply[1] >> 4) as u32);
+
+        let (reader, writer) = tokio::io::split(stream);
+        let writer: Writer = Arc::new(Mutex::new(Box::new(writer)));
+        let (messages_sender, messages) = mpsc::unbounded_channel();
+        let max_receive_length = 1 << (9 + exponent as u32);
+        tokio::spawn(read_messages(Box::new(reader), writer.clone(), max_receive_length, messages_sender));
+
+        Ok(RawSocketTransport { writer, messages, max_send_length })
+    }
+}
+
+impl Transport for RawSocketTransport {
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let message = message.as_bytes();
+            if message.len() > self.max_send_length as usize {
+                return Err(Error::Protocol(format!("message of {} bytes exceeds the router's limit of {}", message.len(), self.max_send_length)));
+            }
+            write_frame(&mut **self.writer.lock().await, FRAME_MESSAGE, message).await?;
+            Ok(())
+        })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, Error>>> {
+        Box::pin(self.messages.recv())
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            self.writer.lock().await.shutdown().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Smallest exponent `l` with `2^(9 + l) >= length`, capped at the 4 bits the handshake has.
+fn length_exponent(length: u32) -> u8 {
+    (0..15).find(|l| 1u32 << (9 + l) >= length).unwrap_or(15)
+}
+
+fn handshake_error(code: u8) -> &'static str {
+    match code {
+        1 => "serializer unsupported",
+        2 => "maximum message length unacceptable",
+        3 => "use of reserved bits",
+        4 => "maximum connection count reached",
+        _ => "illegal handshake"
+    }
+}
+
+async fn write_frame(writer: &mut (dyn AsyncWrite + Send + Unpin), frame_type: u8, payload: &[u8]) -> std::io::Result<()> {
+    let length = (payload.len() as u32).to_be_bytes();
+    writer.write_all(&[frame_type, length[1], length[2], length[3]]).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+/// Reads frames until the stream ends, answering pings on the way. Reading a frame is not
+/// cancel-safe, so this runs on its own task and `receive` reads from the channel.
+async fn read_messages(mut reader: Reader, writer: Writer, max_length: u32, messages: mpsc::UnboundedSender<Result<Message, Error>>) {
+    loop {
+        let mut header = [0; 4];
+        if reader.read_exact(&mut header).await.is_err() {
+            break;
+        }
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        if length > max_length {
+            let _ = messages.send(Err(Error::Protocol(format!("received a frame of {length} bytes, over the limit of {max_length}"))));
+            break;
+        }
+        let mut payload = vec![0; length as usize];
+        if let Err(error) = reader.read_exact(&mut payload).await {
+            let _ = messages.send(Err(error.into()));
+            break;
+        }
+
+        let sent = match header[0] & 0x07 {
+            FRAME_MESSAGE => messages.send(Ok(Message::Binary(payload))),
+            FRAME_PING => {
+                let _ = write_frame(&mut **writer.lock().await, FRAME_PONG, &payload).await;
+                Ok(())
+            },
+            FRAME_PONG => Ok(()),
+            other => {
+                let _ = messages.send(Err(Error::Protocol(format!("unknown rawsocket frame type {other}"))));
+                break;
+            }
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIALIZER_JSON: u8 = 1;
+
+    #[test]
+    fn rounds_length_up_to_the_next_exponent() {
+        assert_eq!(length_exponent(512), 0);
+        assert_eq!(length_exponent(513), 1);
+        assert_eq!(length_exponent(MAX_MESSAGE_LENGTH), 15);
+        assert_eq!(length_exponent(u32::MAX), 15);
+    }
+
+    #[tokio::test]
+    async fn negotiates_and_exchanges_frames() {
+        let (client, mut router) = tokio::io::duplex(4096);
+        let handshake = tokio::spawn(RawSocketTransport::handshake(client, SERIALIZER_JSON, 1 << 16));
+
+        let mut request = [0; 4];
+        router.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, [MAGIC, (7 << 4) | SERIALIZER_JSON, 0, 0]);
+        router.write_all(&[MAGIC, (1 << 4) | SERIALIZER_JSON, 0, 0]).await.unwrap();
+        let mut transport = handshake.await.unwrap().unwrap();
+        assert_eq!(transport.max_send_length, 1024);
+
+        router.write_all(&[FRAME_PING, 0, 0, 2, b'h', b'i']).await.unwrap();
+        router.write_all(&[&[FRAME_MESSAGE, 0, 0, 10], br#"[6,{},"x"]"#.as_slice()].concat()).await.unwrap();
+        let mut pong = [0; 6];
+        router.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [FRAME_PONG, 0, 0, 2, b'h', b'i']);
+        assert_eq!(transport.receive().await.unwrap().unwrap().as_bytes(), br#"[6,{},"x"]"#);
+
+        transport.send(Message::Text("[1]".to_string())).await.unwrap();
+        let mut frame = [0; 7];
+        router.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [FRAME_MESSAGE, 0, 0, 3, b'[', b'1', b']']);
+        assert!(transport.send(Message::Binary(vec![0; 1025])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_a_refused_handshake() {
+        let (client, mut router) = tokio::io::duplex(64);
+        router.write_all(&[MAGIC, 1 << 4, 0, 0]).await.unwrap();
+        match RawSocketTransport::handshake(client, SERIALIZER_JSON, MAX_MESSAGE_LENGTH).await {
+            Err(Error::Handshake(reason)) => assert_eq!(reason, "serializer unsupported"),
+            _ => panic!("handshake should fail")
+        }
+    }
+}
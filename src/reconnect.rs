@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use crate::session::SessionDetails;
+
+/// How the session driver reconnects after the transport fails. The delay before attempt
+/// `n` is `initial_delay * multiplier^(n - 1)`, capped at `max_delay` and spread by
+/// `jitter` (a fraction of the delay) in both directions.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+    /// Attempts before giving up, or `None` to retry forever.
+    pub max_attempts: Option<u32>
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.1,
+            max_attempts: Some(15)
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let spread = base * self.jitter.clamp(0.0, 1.0);
+        let jittered = base - spread + rand::random::<f64>() * 2.0 * spread;
+        Duration::from_secs_f64(jittered.clamp(0.0, self.max_delay.as_secs_f64()))
+    }
+
+    pub(crate) fn exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+}
+
+/// Connection state changes reported through `Events::Lifecycle`.
+#[derive(Debug, Clone)]
+pub enum Lifecycle {
+    /// The transport failed. Pending requests have been failed with `Error::SessionClosed`,
+    /// except subscribes and registers, which are answered by the restored session.
+    Disconnected,
+    /// Waiting `delay` before reconnect attempt `attempt`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The realm was joined again and subscriptions and registrations were restored.
+    Rejoined(Box<SessionDetails>),
+    /// The session is over: it was left or closed, the router said GOODBYE, or, with a
+    /// reconnect policy, the policy ran out of attempts or the router refused the new session.
+    Closed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_and_caps() {
+        let policy = ReconnectPolicy { jitter: 0.0, ..ReconnectPolicy::default() };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(30), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn delay_stays_within_jitter() {
+        let policy = ReconnectPolicy { jitter: 0.5, ..ReconnectPolicy::default() };
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(3));
+        }
+    }
+
+    #[test]
+    fn max_attempts() {
+        let policy = ReconnectPolicy { max_attempts: Some(2), ..ReconnectPolicy::default() };
+        assert!(!policy.exhausted(2));
+        assert!(policy.exhausted(3));
+        assert!(!ReconnectPolicy { max_attempts: None, ..policy }.exhausted(u32::MAX));
+    }
+}
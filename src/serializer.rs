@@ -0,0 +1,222 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ciborium::value::Value as CborValue;
+use json::JsonValue;
+use rmpv::Value as MsgPackValue;
+
+use crate::{error::Error, transport::Message};
+
+/// Converts WAMP messages to and from the bytes a transport carries. Binary values are
+/// represented in `JsonValue` as strings starting with `\0` followed by their base64
+/// encoding, the convention WAMP uses for binary data in JSON.
+pub trait Serializer: Send + Sync {
+    /// WebSocket subprotocol announcing this serializer.
+    fn subprotocol(&self) -> &'static str;
+
+    /// Serializer id announced in the RawSocket handshake.
+    fn rawsocket_id(&self) -> u8;
+
+    fn serialize(&self, message: &JsonValue) -> Result<Message, Error>;
+
+    fn deserialize(&self, message: &Message) -> Result<JsonValue, Error>;
+}
+
+pub struct Json;
+
+pub struct MsgPack;
+
+pub struct Cbor;
+
+impl Serializer for Json {
+    fn subprotocol(&self) -> &'static str {
+        "wamp.2.json"
+    }
+
+    fn rawsocket_id(&self) -> u8 {
+        1
+    }
+
+    fn serialize(&self, message: &JsonValue) -> Result<Message, Error> {
+        Ok(Message::Text(message.dump()))
+    }
+
+    fn deserialize(&self, message: &Message) -> Result<JsonValue, Error> {
+        let text = std::str::from_utf8(message.as_bytes()).map_err(|error| Error::Serialization(error.to_string()))?;
+        json::parse(text).map_err(|error| Error::Serialization(error.to_string()))
+    }
+}
+
+impl Serializer for MsgPack {
+    fn subprotocol(&self) -> &'static str {
+        "wamp.2.msgpack"
+    }
+
+    fn rawsocket_id(&self) -> u8 {
+        2
+    }
+
+    fn serialize(&self, message: &JsonValue) -> Result<Message, Error> {
+        let mut bytes = vec![];
+        rmpv::encode::write_value(&mut bytes, &to_msgpack(message)).map_err(|error| Error::Serialization(error.to_string()))?;
+        Ok(Message::Binary(bytes))
+    }
+
+    fn deserialize(&self, message: &Message) -> Result<JsonValue, Error> {
+        let value = rmpv::decode::read_value(&mut message.as_bytes()).map_err(|error| Error::Serialization(error.to_string()))?;
+        from_msgpack(value)
+    }
+}
+
+impl Serializer for Cbor {
+    fn subprotocol(&self) -> &'static str {
+        "wamp.2.cbor"
+    }
+
+    fn rawsocket_id(&self) -> u8 {
+        3
+    }
+
+    fn serialize(&self, message: &JsonValue) -> Result<Message, Error> {
+        let mut bytes = vec![];
+        ciborium::into_writer(&to_cbor(message), &mut bytes).map_err(|error| Error::Serialization(error.to_string()))?;
+        Ok(Message::Binary(bytes))
+    }
+
+    fn deserialize(&self, message: &Message) -> Result<JsonValue, Error> {
+        let value: CborValue = ciborium::from_reader(message.as_bytes()).map_err(|error| Error::Serialization(error.to_string()))?;
+        from_cbor(value)
+    }
+}
+
+/// Decodes a `\0`-prefixed base64 string into the bytes it stands for.
+pub(crate) fn binary(value: &str) -> Option<Vec<u8>> {
+    STANDARD.decode(value.strip_prefix('\0')?).ok()
+}
+
+pub(crate) fn binary_string(bytes: &[u8]) -> JsonValue {
+    format!("\0{}", STANDARD.encode(bytes)).into()
+}
+
+fn to_msgpack(value: &JsonValue) -> MsgPackValue {
+    match value {
+        JsonValue::Null => MsgPackValue::Nil,
+        JsonValue::Boolean(boolean) => MsgPackValue::Boolean(*boolean),
+        JsonValue::Number(_) => match (value.as_u64(), value.as_i64()) {
+            (Some(unsigned), _) => unsigned.into(),
+            (_, Some(signed)) => signed.into(),
+            _ => MsgPackValue::F64(value.as_f64().unwrap_or_default())
+        },
+        JsonValue::Short(_) | JsonValue::String(_) => {
+            let string = value.as_str().unwrap_or_default();
+            binary(string).map(MsgPackValue::Binary).unwrap_or_else(|| string.into())
+        },
+        JsonValue::Array(items) => MsgPackValue::Array(items.iter().map(to_msgpack).collect()),
+        JsonValue::Object(object) => MsgPackValue::Map(object.iter().map(|(key, value)| (key.into(), to_msgpack(value))).collect())
+    }
+}
+
+fn from_msgpack(value: MsgPackValue) -> Result<JsonValue, Error> {
+    Ok(match value {
+        MsgPackValue::Nil => JsonValue::Null,
+        MsgPackValue::Boolean(boolean) => boolean.into(),
+        MsgPackValue::Integer(integer) => match (integer.as_u64(), integer.as_i64()) {
+            (Some(unsigned), _) => unsigned.into(),
+            (_, Some(signed)) => signed.into(),
+            _ => integer.as_f64().unwrap_or_default().into()
+        },
+        MsgPackValue::F32(float) => float.into(),
+        MsgPackValue::F64(float) => float.into(),
+        MsgPackValue::String(string) => string.into_str()
+            .ok_or_else(|| Error::Serialization("string is not valid UTF-8".to_string()))?
+            .into(),
+        MsgPackValue::Binary(bytes) => binary_string(&bytes),
+        MsgPackValue::Array(items) => JsonValue::Array(items.into_iter().map(from_msgpack).collect::<Result<_, _>>()?),
+        MsgPackValue::Map(entries) => {
+            let mut object = JsonValue::new_object();
+            for (key, value) in entries {
+                let key = key.as_str().ok_or_else(|| Error::Serialization("map key is not a string".to_string()))?.to_string();
+                object[key] = from_msgpack(value)?;
+            }
+            object
+        },
+        MsgPackValue::Ext(..) => return Err(Error::Serialization("msgpack extension types are not supported".to_string()))
+    })
+}
+
+fn to_cbor(value: &JsonValue) -> CborValue {
+    match value {
+        JsonValue::Null => CborValue::Null,
+        JsonValue::Boolean(boolean) => CborValue::Bool(*boolean),
+        JsonValue::Number(_) => match (value.as_u64(), value.as_i64()) {
+            (Some(unsigned), _) => CborValue::Integer(unsigned.into()),
+            (_, Some(signed)) => CborValue::Integer(signed.into()),
+            _ => CborValue::Float(value.as_f64().unwrap_or_default())
+        },
+        JsonValue::Short(_) | JsonValue::String(_) => {
+            let string = value.as_str().unwrap_or_default();
+            binary(string).map(CborValue::Bytes).unwrap_or_else(|| CborValue::Text(string.to_string()))
+        },
+        JsonValue::Array(items) => CborValue::Array(items.iter().map(to_cbor).collect()),
+        JsonValue::Object(object) => CborValue::Map(object.iter().map(|(key, value)| (CborValue::Text(key.to_string()), to_cbor(value))).collect())
+    }
+}
+
+fn from_cbor(value: CborValue) -> Result<JsonValue, Error> {
+    Ok(match value {
+        CborValue::Null => JsonValue::Null,
+        CborValue::Bool(boolean) => boolean.into(),
+        CborValue::Integer(integer) => {
+            let integer = i128::from(integer);
+            match (u64::try_from(integer), i64::try_from(integer)) {
+                (Ok(unsigned), _) => unsigned.into(),
+                (_, Ok(signed)) => signed.into(),
+                _ => (integer as f64).into()
+            }
+        },
+        CborValue::Float(float) => float.into(),
+        CborValue::Text(text) => text.into(),
+        CborValue::Bytes(bytes) => binary_string(&bytes),
+        CborValue::Tag(_, value) => from_cbor(*value)?,
+        CborValue::Array(items) => JsonValue::Array(items.into_iter().map(from_cbor).collect::<Result<_, _>>()?),
+        CborValue::Map(entries) => {
+            let mut object = JsonValue::new_object();
+            for (key, value) in entries {
+                let CborValue::Text(key) = key else {
+                    return Err(Error::Serialization("map key is not a string".to_string()));
+                };
+                object[key] = from_cbor(value)?;
+            }
+            object
+        },
+        _ => return Err(Error::Serialization("unsupported CBOR value".to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(serializer: &dyn Serializer) {
+        let message = json::array![
+            36, 5, 18446744073709551615u64, {"topic": "com.example"},
+            [-3, 1.5, null, true, "text", "\0AQID"],
+            {"nested": [{}]}
+        ];
+        let serialized = serializer.serialize(&message).unwrap();
+        assert_eq!(serializer.deserialize(&serialized).unwrap(), message);
+    }
+
+    #[test]
+    fn roundtrips_through_every_serializer() {
+        roundtrip(&Json);
+        roundtrip(&MsgPack);
+        roundtrip(&Cbor);
+    }
+
+    #[test]
+    fn binary_strings_become_native_bytes() {
+        let Message::Binary(bytes) = MsgPack.serialize(&json::array!["\0AQID"]).unwrap() else {
+            panic!("msgpack should serialize to a binary message");
+        };
+        assert_eq!(bytes, [0x91, 0xC4, 3, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,90 @@
+use std::{future::Future, sync::Arc};
+
+use wamp_helpers::messages::Invocation;
+
+use crate::callback::{AsyncProcedure, InvocationResult};
+
+/// A procedure ready to be registered, as generated by `#[procedure]` and `#[service]`.
+pub struct ProcedureDefinition {
+    pub uri: String,
+    pub(crate) procedure: AsyncProcedure
+}
+
+impl ProcedureDefinition {
+    pub fn new<F, Fut>(uri: &str, procedure: F) -> ProcedureDefinition
+    where
+        F: Fn(Invocation) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = InvocationResult> + Send + 'static
+    {
+        ProcedureDefinition {
+            uri: uri.to_string(),
+            procedure: Box::new(move |invocation| Box::pin(procedure(invocation)))
+        }
+    }
+}
+
+/// A set of procedures mounted together with `WampClient::mount`. Implemented by the
+/// `#[service]` attribute for an `impl` block whose methods are marked `#[procedure]`.
+pub trait Service: Send + Sync + 'static {
+    fn procedures(self: Arc<Self>) -> Vec<ProcedureDefinition>;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        callback::{InvocationContext, ProcedureError},
+        client::WampClient,
+        options::{InvokePolicy, RegisterOptions},
+        procedure,
+        serializer::Json,
+        service,
+        transport::loopback
+    };
+
+    struct Calculator {
+        offset: i64
+    }
+
+    #[service]
+    impl Calculator {
+        #[procedure("com.example.add")]
+        async fn add(&self, a: i64, b: i64) -> Result<i64, ProcedureError> {
+            Ok(self.offset + a + b)
+        }
+    }
+
+    #[procedure("com.example.echo")]
+    async fn echo(text: String, call: InvocationContext, times: Option<usize>) -> Result<String, ProcedureError> {
+        if call.caller_authrole.as_deref() == Some("guest") {
+            return Err(ProcedureError::new("wamp.error.not_authorized"));
+        }
+        Ok(text.repeat(times.unwrap_or(1)))
+    }
+
+    #[tokio::test]
+    async fn mounts_services_and_answers_invocations() {
+        let (transport, mut router) = loopback();
+        let client = WampClient::with_transport(transport, Json);
+        let mounted = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.mount(Calculator { offset: 1 }, InvokePolicy::RoundRobin).await?;
+                client.register_procedure(echo_procedure(), RegisterOptions::default()).await
+            }
+        });
+
+        assert_eq!(router.answer("[65, 1, 100]").await, json::array![64, 1, { invoke: "roundrobin" }, "com.example.add"]);
+        assert_eq!(router.answer("[65, 2, 200]").await, json::array![64, 2, {}, "com.example.echo"]);
+        mounted.await.unwrap().unwrap();
+
+        router.reply("[68, 7, 100, {}, [1, 2]]").await;
+        assert_eq!(router.expect().await, json::array![70, 7, {}, [4]]);
+        router.reply(r#"[68, 8, 200, {}, [], {"text": "ab", "times": 2}]"#).await;
+        assert_eq!(router.expect().await, json::array![70, 8, {}, ["abab"]]);
+        router.reply(r#"[68, 9, 100, {}, ["x", 2]]"#).await;
+        let error = router.expect().await;
+        assert_eq!((error[0].as_u64(), error[4].as_str()), (Some(8), Some("wamp.error.invalid_argument")));
+        router.reply(r#"[68, 10, 200, {"caller": 3, "caller_authrole": "guest"}, ["ab"]]"#).await;
+        assert_eq!(router.expect().await[4].as_str(), Some("wamp.error.not_authorized"));
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use json::JsonValue;
+use wamp_helpers::messages::Welcome;
+
+use crate::auth::Authenticator;
+
+/// Client roles announced in HELLO.
+#[derive(Debug, Clone, Copy)]
+pub struct Roles {
+    pub caller: bool,
+    pub callee: bool,
+    pub publisher: bool,
+    pub subscriber: bool
+}
+
+impl Default for Roles {
+    fn default() -> Self {
+        Roles { caller: true, callee: true, publisher: true, subscriber: true }
+    }
+}
+
+impl Roles {
+    /// The `roles` dictionary of HELLO, with the features this client implements.
+    pub fn to_json(&self) -> JsonValue {
+        let mut roles = json::object! {};
+        if self.caller {
+            roles["caller"] = json::object! {
+                features: {
+                    progressive_call_results: true,
+                    call_canceling: true,
+                    caller_identification: true,
+                    payload_passthru_mode: true
+                }
+            };
+        }
+        if self.callee {
+            roles["callee"] = json::object! {
+                features: {
+                    progressive_call_results: true,
+                    call_canceling: true,
+                    shared_registration: true,
+                    pattern_based_registration: true,
+                    caller_identification: true,
+                    call_trustlevels: true,
+                    payload_passthru_mode: true
+                }
+            };
+        }
+        if self.publisher {
+            roles["publisher"] = json::object! {
+                features: {
+                    publisher_exclusion: true,
+                    subscriber_blackwhite_listing: true,
+                    publisher_identification: true,
+                    payload_passthru_mode: true
+                }
+            };
+        }
+        if self.subscriber {
+            roles["subscriber"] = json::object! {
+                features: {
+                    pattern_based_subscription: true,
+                    publisher_identification: true,
+                    publication_trustlevels: true,
+                    payload_passthru_mode: true
+                }
+            };
+        }
+        roles
+    }
+}
+
+pub(crate) fn hello_details(roles: &Roles, authenticators: &[Arc<dyn Authenticator>]) -> JsonValue {
+    let mut details = json::object! {
+        roles: roles.to_json(),
+        authmethods: authenticators.iter().map(|a| a.authmethod()).collect::<Vec<_>>()
+    };
+    if let Some(authid) = authenticators.iter().find_map(|a| a.authid()) {
+        details["authid"] = authid.into();
+    }
+    let mut authextra = json::object! {};
+    for extra in authenticators.iter().filter_map(|a| a.authextra()) {
+        for (key, value) in extra.entries() {
+            authextra[key] = value.clone();
+        }
+    }
+    if !authextra.is_empty() {
+        details["authextra"] = authextra;
+    }
+    details
+}
+
+/// Features a router role announced in WELCOME.
+#[derive(Debug, Clone)]
+pub struct RoleFeatures(pub JsonValue);
+
+impl RoleFeatures {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.0["features"][feature].as_bool().unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RouterRoles {
+    pub broker: Option<RoleFeatures>,
+    pub dealer: Option<RoleFeatures>
+}
+
+/// Session established by `WampClient::join`.
+#[derive(Debug, Clone)]
+pub struct SessionDetails {
+    pub session: u64,
+    pub realm: String,
+    pub authid: Option<String>,
+    pub authrole: Option<String>,
+    pub authmethod: Option<String>,
+    pub authprovider: Option<String>,
+    pub roles: RouterRoles,
+    /// The full WELCOME details, for router specific attributes.
+    pub details: JsonValue
+}
+
+impl SessionDetails {
+    pub(crate) fn new(realm: &str, welcome: Welcome) -> SessionDetails {
+        let string = |key: &str| welcome.details[key].as_str().map(|s| s.to_string());
+        let role = |key: &str| {
+            let role = &welcome.details["roles"][key];
+            if role.is_null() {
+                None
+            } else {
+                Some(RoleFeatures(role.clone()))
+            }
+        };
+
+        SessionDetails {
+            session: welcome.session,
+            realm: realm.to_string(),
+            authid: string("authid"),
+            authrole: string("authrole"),
+            authmethod: string("authmethod"),
+            authprovider: string("authprovider"),
+            roles: RouterRoles { broker: role("broker"), dealer: role("dealer") },
+            details: welcome.details
+        }
+    }
+}
+
+/// Largest request id WAMP allows, 2^53.
+const MAX_REQUEST_ID: u64 = 1 << 53;
+
+/// Sequential request ids of one session, shared by its handles and handler contexts.
+/// Ids start at 1 and wrap back to 1 after 2^53.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequestIds(Arc<AtomicU64>);
+
+impl RequestIds {
+    pub(crate) fn next(&self) -> u64 {
+        let following = |id: u64| if id >= MAX_REQUEST_ID { 1 } else { id + 1 };
+        let previous = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| Some(following(id)))
+            .unwrap_or_else(|id| id);
+        following(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_wrap_within_the_spec_range() {
+        let ids = RequestIds::default();
+        assert_eq!((ids.next(), ids.clone().next()), (1, 2));
+
+        ids.0.store(MAX_REQUEST_ID - 1, Ordering::Relaxed);
+        assert_eq!((ids.next(), ids.next()), (MAX_REQUEST_ID, 1));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{future::Future, pin::Pin};
+
+use tokio::sync::mpsc;
+use websockets::{Frame, WebSocket, WebSocketError, WebSocketReadHalf, WebSocketWriteHalf};
+
+use crate::error::Error;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A serialized WAMP message, as produced by a `Serializer`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>)
+}
+
+impl Message {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(bytes) => bytes
+        }
+    }
+}
+
+/// Connection carrying serialized WAMP messages between the client and a router.
+pub trait Transport: Send {
+    /// Writes one message.
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Waits for the next message, or `None` once the connection is gone. The session
+    /// driver polls this inside `select!`, so it must be cancel-safe: dropping the
+    /// future must not lose a message.
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, Error>>>;
+
+    /// Closes the connection cleanly. The default just lets it drop.
+    fn close(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// WAMP over a WebSocket, one text or binary frame per message.
+pub struct WebSocketTransport {
+    writer: WebSocketWriteHalf,
+    frames: mpsc::UnboundedReceiver<Result<Frame, WebSocketError>>
+}
+
+impl WebSocketTransport {
+    pub fn new(websocket: WebSocket) -> WebSocketTransport {
+        let (reader, writer) = websocket.split();
+        let (frames_sender, frames) = mpsc::unbounded_channel();
+        tokio::spawn(read_frames(reader, frames_sender));
+        WebSocketTransport { writer, frames }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            match message {
+                Message::Text(text) => self.writer.send_text(text).await?,
+                Message::Binary(bytes) => self.writer.send_binary(bytes).await?
+            }
+            Ok(())
+        })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, Error>>> {
+        Box::pin(async move {
+            loop {
+                match self.frames.recv().await? {
+                    Ok(frame) => {
+                        if let Some((text, _, _)) = frame.as_text() {
+                            return Some(Ok(Message::Text(text.clone())));
+                        }
+                        if let Some((bytes, _, _)) = frame.as_binary() {
+                            return Some(Ok(Message::Binary(bytes.clone())));
+                        }
+                    },
+                    Err(error) => return Some(Err(error.into()))
+                }
+            }
+        })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            self.writer.close(None).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Forwards frames from the socket until it fails. `WebSocketReadHalf::receive` cannot be
+/// cancelled safely, so it runs on its own task and `receive` reads from the channel.
+async fn read_frames(mut reader: WebSocketReadHalf, frames: mpsc::UnboundedSender<Result<Frame, WebSocketError>>) {
+    loop {
+        let frame = reader.receive().await;
+        let failed = frame.is_err();
+        if frames.send(frame).is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// In-memory transport. Messages sent on one end of a `loopback` pair are received on
+/// the other, which lets tests play the router without a network.
+pub struct LoopbackTransport {
+    sender: mpsc::UnboundedSender<Message>,
+    receiver: mpsc::UnboundedReceiver<Message>
+}
+
+/// Creates two connected loopback transports.
+pub fn loopback() -> (LoopbackTransport, LoopbackTransport) {
+    let (client_sender, router_receiver) = mpsc::unbounded_channel();
+    let (router_sender, client_receiver) = mpsc::unbounded_channel();
+    (
+        LoopbackTransport { sender: client_sender, receiver: client_receiver },
+        LoopbackTransport { sender: router_sender, receiver: router_receiver }
+    )
+}
+
+/// Scripted router for tests, speaking JSON to the client on the other end.
+#[cfg(test)]
+impl LoopbackTransport {
+    /// Next message the client sent.
+    pub(crate) async fn expect(&mut self) -> json::JsonValue {
+        use crate::serializer::{Json, Serializer};
+        Json.deserialize(&self.receive().await.unwrap().unwrap()).unwrap()
+    }
+
+    /// Sends the JSON `message` to the client.
+    pub(crate) async fn reply(&mut self, message: &str) {
+        self.send(Message::Text(message.to_string())).await.unwrap();
+    }
+
+    /// Answers the next message the client sends with `reply`, returning that message.
+    pub(crate) async fn answer(&mut self, reply: &str) -> json::JsonValue {
+        let request = self.expect().await;
+        self.reply(reply).await;
+        request
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), Error>> {
+        let sent = self.sender.send(message).map_err(|_| Error::SessionClosed);
+        Box::pin(async move { sent })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, Error>>> {
+        Box::pin(async move { self.receiver.recv().await.map(Ok) })
+    }
+}
@@ -0,0 +1,102 @@
+use json::JsonValue;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{callback::{InvocationResult, Payload, ProcedureError}, error::Error};
+
+/// Splits a value into WAMP `args` and `kwargs`. Structs and maps become `kwargs`,
+/// sequences and tuples become `args`, and any other value is sent as the single
+/// positional argument. `()` sends no payload at all.
+pub fn to_payload<T: Serialize + ?Sized>(value: &T) -> Result<(JsonValue, JsonValue), Error> {
+    let value = serde_json::to_value(value).map_err(Error::Payload)?;
+    let converted = to_json(&value)?;
+    Ok(match value {
+        serde_json::Value::Null => (JsonValue::Null, JsonValue::Null),
+        serde_json::Value::Object(_) => (json::array![], converted),
+        serde_json::Value::Array(_) => (converted, JsonValue::Null),
+        _ => (json::array![converted], JsonValue::Null)
+    })
+}
+
+/// Reads a value back from WAMP `args` and `kwargs`, the inverse of `to_payload`.
+/// Non-empty `kwargs` are decoded as a struct or map. Otherwise a single positional
+/// argument is decoded on its own, falling back to the whole `args` array.
+pub fn from_payload<T: DeserializeOwned>(args: &JsonValue, kwargs: &JsonValue) -> Result<T, Error> {
+    if kwargs.is_object() && !kwargs.is_empty() {
+        return from_json(kwargs);
+    }
+    if args.is_array() && args.len() == 1 {
+        if let Ok(value) = from_json(&args[0]) {
+            return Ok(value);
+        }
+    }
+    if args.is_null() || args.is_empty() {
+        return from_json(&JsonValue::Null);
+    }
+    from_json(args)
+}
+
+/// Decodes one parameter of a procedure: the positional argument at `index` when there
+/// is one, otherwise the keyword argument `name`. A missing argument decodes from null,
+/// so `Option` parameters are optional.
+pub fn argument<T: DeserializeOwned>(args: &JsonValue, kwargs: &JsonValue, index: usize, name: &str) -> Result<T, Error> {
+    if args.is_array() && index < args.len() {
+        return from_json(&args[index]);
+    }
+    from_json(&kwargs[name])
+}
+
+/// Return values of `#[procedure]` functions.
+pub trait IntoInvocationResult {
+    fn into_invocation_result(self) -> InvocationResult;
+}
+
+impl<T: Serialize, E: Into<ProcedureError>> IntoInvocationResult for Result<T, E> {
+    fn into_invocation_result(self) -> InvocationResult {
+        let value = self.map_err(Into::into)?;
+        Payload::typed(&value).map_err(ProcedureError::from)
+    }
+}
+
+fn to_json(value: &serde_json::Value) -> Result<JsonValue, Error> {
+    json::parse(&value.to_string()).map_err(|error| Error::Serialization(error.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(value: &JsonValue) -> Result<T, Error> {
+    serde_json::from_str(&value.dump()).map_err(Error::Payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        nick: String,
+        text: String
+    }
+
+    #[test]
+    fn structs_travel_as_kwargs_and_tuples_as_args() {
+        let message = Message { nick: "joe".to_string(), text: "hi".to_string() };
+        let (args, kwargs) = to_payload(&message).unwrap();
+        assert_eq!((&args, &kwargs), (&json::array![], &json::object! { nick: "joe", text: "hi" }));
+        assert_eq!(from_payload::<Message>(&args, &kwargs).unwrap(), message);
+
+        let (args, kwargs) = to_payload(&(1, "two")).unwrap();
+        assert_eq!((&args, &kwargs), (&json::array![1, "two"], &JsonValue::Null));
+        assert_eq!(from_payload::<(u32, String)>(&args, &kwargs).unwrap(), (1, "two".to_string()));
+
+        assert_eq!(from_payload::<u64>(&json::array![42], &JsonValue::Null).unwrap(), 42);
+        from_payload::<()>(&JsonValue::Null, &JsonValue::Null).unwrap();
+    }
+
+    #[test]
+    fn reports_mismatched_payloads() {
+        match from_payload::<Message>(&json::array!["hi"], &JsonValue::Null) {
+            Err(Error::Payload(_)) => (),
+            other => panic!("unexpected result {other:?}")
+        }
+    }
+}
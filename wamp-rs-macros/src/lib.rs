@@ -0,0 +1,145 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, spanned::Spanned, Error, FnArg, ImplItem, ItemFn, ItemImpl, LitStr, Pat, Signature, Type};
+
+/// Turns an `async fn` into a WAMP procedure. Next to the function, a
+/// `<name>_procedure()` function is generated returning the
+/// `wamp_rs::service::ProcedureDefinition` to pass to `WampClient::register_procedure`.
+///
+/// Each parameter is decoded from the positional argument at its index, or else from
+/// the keyword argument with its name. A parameter of type `InvocationContext` gets the
+/// details of the call instead and takes no argument. The function must return a
+/// `Result` whose value is `Serialize` and whose error converts into `ProcedureError`.
+///
+/// Inside an `impl` block marked `#[service]`, the attribute marks the methods that
+/// the service registers.
+#[proc_macro_attribute]
+pub fn procedure(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let uri = parse_macro_input!(attr as LitStr);
+    let function = parse_macro_input!(item as ItemFn);
+    free_procedure(&uri, &function).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Implements `wamp_rs::service::Service` for the type of an `impl` block, registering
+/// every method marked `#[procedure("uri")]`. Methods take `&self`; the service is
+/// shared between invocations.
+#[proc_macro_attribute]
+pub fn service(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let block = parse_macro_input!(item as ItemImpl);
+    service_impl(block).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn free_procedure(uri: &LitStr, function: &ItemFn) -> syn::Result<TokenStream2> {
+    check_async(&function.sig)?;
+    if let Some(FnArg::Receiver(receiver)) = function.sig.inputs.first() {
+        return Err(Error::new(receiver.span(), "methods are registered through #[service] on their impl block"));
+    }
+
+    let name = &function.sig.ident;
+    let visibility = &function.vis;
+    let definition = format_ident!("{}_procedure", name);
+    let (bindings, arguments) = decode_arguments(&function.sig)?;
+    Ok(quote! {
+        #function
+
+        #[doc = concat!("Definition of the `", #uri, "` procedure.")]
+        #visibility fn #definition() -> ::wamp_rs::service::ProcedureDefinition {
+            ::wamp_rs::service::ProcedureDefinition::new(#uri, |_invocation| async move {
+                #(#bindings)*
+                ::wamp_rs::typed::IntoInvocationResult::into_invocation_result(#name(#(#arguments),*).await)
+            })
+        }
+    })
+}
+
+fn service_impl(mut block: ItemImpl) -> syn::Result<TokenStream2> {
+    let mut definitions = vec![];
+    for item in &mut block.items {
+        let ImplItem::Fn(method) = item else {
+            continue;
+        };
+        let Some(index) = method.attrs.iter().position(|attr| attr.path().segments.last().is_some_and(|s| s.ident == "procedure")) else {
+            continue;
+        };
+        let uri: LitStr = method.attrs.remove(index).parse_args()?;
+        check_async(&method.sig)?;
+        if !matches!(method.sig.inputs.first(), Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_none()) {
+            return Err(Error::new(method.sig.span(), "service procedures take &self"));
+        }
+
+        let name = &method.sig.ident;
+        let (bindings, arguments) = decode_arguments(&method.sig)?;
+        definitions.push(quote! {
+            {
+                let this = self.clone();
+                ::wamp_rs::service::ProcedureDefinition::new(#uri, move |_invocation| {
+                    let this = this.clone();
+                    async move {
+                        #(#bindings)*
+                        ::wamp_rs::typed::IntoInvocationResult::into_invocation_result(this.#name(#(#arguments),*).await)
+                    }
+                })
+            }
+        });
+    }
+
+    let self_ty = &block.self_ty;
+    let (impl_generics, _, where_clause) = block.generics.split_for_impl();
+    Ok(quote! {
+        #block
+
+        impl #impl_generics ::wamp_rs::service::Service for #self_ty #where_clause {
+            fn procedures(self: ::std::sync::Arc<Self>) -> ::std::vec::Vec<::wamp_rs::service::ProcedureDefinition> {
+                ::std::vec![#(#definitions),*]
+            }
+        }
+    })
+}
+
+fn check_async(signature: &Signature) -> syn::Result<()> {
+    match signature.asyncness {
+        Some(_) => Ok(()),
+        None => Err(Error::new(signature.fn_token.span, "procedures must be async"))
+    }
+}
+
+/// Statements decoding each typed parameter from `_invocation`, and the names to call
+/// the function with.
+fn decode_arguments(signature: &Signature) -> syn::Result<(Vec<TokenStream2>, Vec<TokenStream2>)> {
+    let mut bindings = vec![];
+    let mut arguments = vec![];
+    let mut index = 0usize;
+    for input in &signature.inputs {
+        let FnArg::Typed(argument) = input else {
+            continue;
+        };
+        let Pat::Ident(pattern) = &*argument.pat else {
+            return Err(Error::new(argument.pat.span(), "procedure parameters must be plain identifiers"));
+        };
+
+        let ident = &pattern.ident;
+        let name = ident.to_string();
+        let ty = &argument.ty;
+        arguments.push(quote! { #ident });
+        if is_invocation_context(ty) {
+            bindings.push(quote! {
+                let #ident: #ty = ::wamp_rs::callback::InvocationContext::new(&_invocation);
+            });
+            continue;
+        }
+
+        bindings.push(quote! {
+            let #ident: #ty = match ::wamp_rs::typed::argument(&_invocation.args, &_invocation.kwargs, #index, #name) {
+                ::std::result::Result::Ok(value) => value,
+                ::std::result::Result::Err(error) => return ::std::result::Result::Err(::wamp_rs::callback::ProcedureError::from(error))
+            };
+        });
+        index += 1;
+    }
+    Ok((bindings, arguments))
+}
+
+fn is_invocation_context(ty: &Type) -> bool {
+    matches!(ty, Type::Path(path) if path.path.segments.last().is_some_and(|s| s.ident == "InvocationContext"))
+}